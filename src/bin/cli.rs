@@ -4,6 +4,7 @@ use std::io::Write;
 
 use pathfinder2::graph;
 use pathfinder2::io;
+use pathfinder2::stats;
 use pathfinder2::types::Address;
 use pathfinder2::types::U256;
 
@@ -18,12 +19,18 @@ fn main() {
     let (dotfile, mut args) =
         if env::args().len() >= 2 && env::args().nth_back(1).unwrap() == "--dot" {
             (
-                Some(env::args().last().unwrap()),
+                Some(env::args().next_back().unwrap()),
                 env::args().rev().skip(2).rev().collect::<Vec<_>>(),
             )
         } else {
             (None, env::args().collect::<Vec<_>>())
         };
+    let stats = if args.get(1) == Some(&"--stats".to_string()) {
+        args = [vec![args[0].clone()], args[2..].to_vec()].concat();
+        true
+    } else {
+        false
+    };
     let csv = if args.get(1) == Some(&"--csv".to_string()) {
         args = [vec![args[0].clone()], args[2..].to_vec()].concat();
         true
@@ -40,6 +47,13 @@ fn main() {
         println!("Options --safes and --csv cannot be used together.");
         return;
     }
+    if stats {
+        match args.get(1) {
+            Some(file) => print_stats(file, csv, safes),
+            None => println!("Usage: cli --stats [--csv] [--safes] <edges.dat>"),
+        }
+        return;
+    }
 
     if args.len() < 4 {
         println!("Usage: cli [--csv] [--safes] <from> <to> <edges.dat> [--dot <dotfile>]");
@@ -54,6 +68,8 @@ fn main() {
         );
         println!("Option --csv reads edges.dat in csv format instead of binary.");
         println!("Option --safes reads a safes.dat file instead of an edges.dat file.");
+        println!("Usage: cli --stats [--csv] [--safes] <edges.dat>");
+        println!("Option --stats prints statistics about the graph instead of computing a flow.");
         return;
     }
     let mut max_hops = None;
@@ -84,15 +100,48 @@ fn main() {
     })
     .unwrap_or_else(|_| panic!("Error loading edges/safes from file \"{edges_file}\"."));
     println!("Read {} edges", edges.edge_count());
-    let (flow, transfers) = graph::compute_flow(
+    let (flow, transfers) = match graph::compute_flow(
         &Address::from(from_str.as_str()),
         &Address::from(to_str.as_str()),
         &edges,
         max_flow,
         max_hops,
         max_transfers,
-    );
+    ) {
+        Ok(result) => result,
+        Err(e) => {
+            println!("{e}");
+            return;
+        }
+    };
     println!("Found flow: {}", flow.to_decimal());
+    if flow == U256::from(0) {
+        let diagnostics = graph::diagnose_zero_flow(
+            &Address::from(from_str.as_str()),
+            &Address::from(to_str.as_str()),
+            &edges,
+            &graph::FlowOptions {
+                max_distance: max_hops,
+                max_transfers,
+                ..Default::default()
+            },
+        );
+        for reason in &diagnostics.reasons {
+            println!("{reason}");
+        }
+        if let Some(path) = diagnostics.shortest_path {
+            println!("Shortest trust path:");
+            for edge in path {
+                println!(
+                    "  {} -> {} ({}): {}",
+                    edge.from,
+                    edge.to,
+                    edge.token,
+                    edge.capacity.to_decimal()
+                );
+            }
+        }
+    }
     //println!("{:?}", transfers);
 
     let result = json::object! {
@@ -141,3 +190,15 @@ fn main() {
         println!("Wrote dotfile {dotfile}.");
     }
 }
+
+fn print_stats(file: &String, csv: bool, safes: bool) {
+    let stats = (if csv {
+        io::read_edges_csv(file).map(|edges| stats::edge_stats(&edges))
+    } else if safes {
+        io::import_from_safes_binary(file).map(|db| stats::safe_db_stats(&db))
+    } else {
+        io::read_edges_binary(file).map(|edges| stats::edge_stats(&edges))
+    })
+    .unwrap_or_else(|_| panic!("Error loading edges/safes from file \"{file}\"."));
+    println!("{}", stats.to_json().pretty(2));
+}
@@ -1,13 +1,33 @@
+use crate::graph::options::{percent_of, CapacityMargin, FlowOptions};
 use crate::graph::Node;
-use crate::types::edge::EdgeDB;
-use crate::types::{Edge, U256};
-use std::cmp::{max, Reverse};
-use std::collections::{HashMap, HashSet, VecDeque};
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::{Address, Edge, U256};
+use std::cmp::{max, min, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::SystemTime;
 
 pub struct Adjacencies<'a> {
     edges: &'a EdgeDB,
     lazy_adjacencies: HashMap<Node, HashMap<Node, U256>>,
     capacity_adjustments: HashMap<Node, HashMap<Node, U256>>,
+    /// If set, the given address can only spend the given tokens.
+    source_tokens: Option<(Address, BTreeSet<Address>)>,
+    /// Capacities of the edges from `Node::SuperSource` to the sources.
+    super_source_edges: HashMap<Address, U256>,
+    /// Capacities of the edges from the sinks to `Node::SuperSink`.
+    super_sink_edges: HashMap<Address, U256>,
+    /// Edges of the capacity network with a smaller capacity are ignored.
+    min_edge_capacity: U256,
+    /// Safety margin applied to the capacities of the capacity network, and the current time.
+    capacity_margin: Option<(CapacityMargin, SystemTime)>,
+    /// Percentage of the capacity of each edge that can be used,
+    /// and the address whose edges are exempt from it.
+    max_edge_share: Option<(u64, Address)>,
+    /// Changes applied on top of `edges`.
+    overlay: Option<Arc<EdgeOverlay>>,
+    /// If set, only these edges (from, to, token) of the capacity network are used.
+    edge_mask: Option<HashSet<(Address, Address, Address)>>,
 }
 
 // fn pseudo_node(edge: Edge) -> Node {
@@ -35,9 +55,94 @@ impl<'a> Adjacencies<'a> {
             edges,
             lazy_adjacencies: HashMap::new(),
             capacity_adjustments: HashMap::new(),
+            source_tokens: None,
+            super_source_edges: HashMap::new(),
+            super_sink_edges: HashMap::new(),
+            min_edge_capacity: U256::from(0),
+            capacity_margin: None,
+            max_edge_share: None,
+            overlay: None,
+            edge_mask: None,
         }
     }
 
+    /// Creates the flow network for a flow from `source` with the capacity constraints
+    /// of the given options. Token preferences are applied separately using `restrict_source_tokens`.
+    pub fn with_options(edges: &'a EdgeDB, source: &Address, options: &FlowOptions) -> Self {
+        let mut adjacencies = Adjacencies::new(edges);
+        if let Some(min_amount) = options.min_transfer_amount {
+            adjacencies.set_min_edge_capacity(min_amount);
+        }
+        if let Some(margin) = options.capacity_margin {
+            adjacencies.set_capacity_margin(margin, SystemTime::now());
+        }
+        if let Some(percent) = options.max_edge_share {
+            adjacencies.set_max_edge_share(percent, source);
+        }
+        if let Some(overlay) = &options.overlay {
+            adjacencies.set_overlay(overlay.clone());
+        }
+        adjacencies
+    }
+
+    /// Ignores all edges of the capacity network with a capacity below `min_capacity`.
+    pub fn set_min_edge_capacity(&mut self, min_capacity: U256) {
+        self.min_edge_capacity = min_capacity;
+        self.lazy_adjacencies.clear();
+    }
+
+    /// Connects `Node::SuperSource` to the given sources and the given sinks to
+    /// `Node::SuperSink`, with the given capacities.
+    pub fn add_super_source_and_sink(
+        &mut self,
+        sources: HashMap<Address, U256>,
+        sinks: HashMap<Address, U256>,
+    ) {
+        self.lazy_adjacencies.remove(&Node::SuperSource);
+        for sink in sinks.keys() {
+            self.lazy_adjacencies.remove(&Node::Node(*sink));
+        }
+        self.super_source_edges = sources;
+        self.super_sink_edges = sinks;
+    }
+
+    /// Reduces the capacities of all edges of the capacity network by the given margin.
+    /// `now` is used to determine the age of the edge data.
+    pub fn set_capacity_margin(&mut self, margin: CapacityMargin, now: SystemTime) {
+        self.capacity_margin = Some((margin, now));
+        self.lazy_adjacencies.clear();
+    }
+
+    /// Only uses `percent` percent of the capacity of each edge of the capacity network,
+    /// except for the edges sent by `exempt` (usually the source).
+    pub fn set_max_edge_share(&mut self, percent: u64, exempt: &Address) {
+        self.max_edge_share = Some((percent.min(100), *exempt));
+        self.lazy_adjacencies.clear();
+    }
+
+    /// Uses the edges of the capacity network with the changes of `overlay` applied.
+    pub fn set_overlay(&mut self, overlay: Arc<EdgeOverlay>) {
+        self.overlay = Some(overlay);
+        self.lazy_adjacencies.clear();
+    }
+
+    /// Only uses the given edges (from, to, token) of the capacity network.
+    /// `None` removes the restriction. Capacity adjustments made so far are
+    /// removed, since they can be on edges that are no longer used.
+    pub fn restrict_edges(&mut self, edges: Option<HashSet<(Address, Address, Address)>>) {
+        self.edge_mask = edges;
+        self.lazy_adjacencies.clear();
+        self.capacity_adjustments.clear();
+    }
+
+    /// Restricts the tokens `source` can send on the first hop.
+    /// `None` removes the restriction.
+    /// Capacity adjustments made so far are kept.
+    pub fn restrict_source_tokens(&mut self, source: &Address, tokens: Option<BTreeSet<Address>>) {
+        self.source_tokens = tokens.map(|tokens| (*source, tokens));
+        self.lazy_adjacencies.remove(&Node::Node(*source));
+    }
+
     /// Uses Breadth-First Search (BFS) to construct a level graph from the source to the sink.
     ///
     /// This function explores the flow network and assigns a level to each node based on its distance
@@ -88,23 +193,25 @@ impl<'a> Adjacencies<'a> {
         }
     }
 
-    /// Performs a Depth-First Search (DFS) on the level graph to find a blocking flow.
+    /// Performs a Depth-First Search (DFS) on the level graph to find an augmenting path.
     ///
     /// This function searches for augmenting paths in the level graph. It ensures that we only consider
-    /// edges that go from a node to a node of a higher level. The search stops when a blocking flow is found.
+    /// edges that go from a node to a node of a higher level. Once a path to the sink is found,
+    /// the bottleneck capacity is pushed along it and the residual network is updated.
+    /// Calling this repeatedly until it returns `None` yields a blocking flow.
     ///
     /// # Arguments
     ///
-    /// * `current` - The current node being explored.
+    /// * `start` - The node to start the search from (usually the source).
     /// * `sink` - The sink node of the flow network.
     /// * `levels` - A reference to a HashMap containing the levels of each node, as determined by BFS.
-    /// * `flow` - The current flow value being pushed through the path.
+    /// * `flow` - The maximum flow value to push through the path.
     /// * `flow_distribution` - A mutable reference to a HashMap tracking the flow distribution across edges.
     ///
     /// # Returns
     ///
-    /// * `Some(U256)` - The flow value of the found blocking flow.
-    /// * `None` - If no blocking flow is found from the current node to the sink.
+    /// * `Some(U256)` - The flow value pushed along the augmenting path.
+    /// * `None` - If no augmenting path from the current node to the sink is found.
     pub fn dfs_search_blocking_flow(
         &mut self,
         start: &Node,
@@ -116,29 +223,35 @@ impl<'a> Adjacencies<'a> {
         if !levels.contains_key(sink) {
             return None; // Exit the search when the sink is not reachable
         }
+        let mut parents: HashMap<Node, (Node, U256)> = HashMap::new();
         let mut stack = vec![(start.clone(), flow)];
         let mut visited = HashSet::new();
 
         while let Some((current, current_flow)) = stack.pop() {
             if &current == sink {
+                let parents = parents
+                    .into_iter()
+                    .map(|(node, (parent, _))| (node, parent))
+                    .collect();
+                self.augment(&parents, sink, current_flow, flow_distribution);
                 return Some(current_flow);
             }
 
-            if visited.contains(&current) {
+            if !visited.insert(current.clone()) {
                 continue;
             }
-            visited.insert(current.clone());
 
-            for (neighbor, capacity) in self.adjacencies_from(&current) {
-                if levels[&neighbor] == levels[&current] + 1 && capacity > U256::from(0) {
+            // Push in reverse order so that the edge with the highest capacity is explored first.
+            for (neighbor, capacity) in self
+                .outgoing_edges_sorted_by_capacity(&current)
+                .into_iter()
+                .rev()
+            {
+                if levels.get(&neighbor) == Some(&(levels[&current] + 1))
+                    && !visited.contains(&neighbor)
+                {
                     let new_flow = U256::min(current_flow, capacity);
-                    self.adjust_capacity(&current, &neighbor, -new_flow);
-                    self.adjust_capacity(&neighbor, &current, new_flow);
-                    *flow_distribution
-                        .entry(current.clone())
-                        .or_default()
-                        .entry(neighbor.clone())
-                        .or_insert(U256::from(0)) += new_flow;
+                    parents.insert(neighbor.clone(), (current.clone(), new_flow));
                     stack.push((neighbor, new_flow));
                 }
             }
@@ -147,6 +260,83 @@ impl<'a> Adjacencies<'a> {
         None
     }
 
+    /// Finds the path with the largest bottleneck capacity (capped at `flow`) in the
+    /// residual network, preferring shorter paths among equally wide ones,
+    /// and pushes the bottleneck capacity along it.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The source node of the flow network.
+    /// * `sink` - The sink node of the flow network.
+    /// * `max_distance` - An optional maximum distance constraint.
+    /// * `flow` - The maximum flow value to push through the path.
+    /// * `flow_distribution` - A mutable reference to a HashMap tracking the flow distribution across edges.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(U256)` - The flow value pushed along the path.
+    /// * `None` - If there is no path from the source to the sink.
+    pub fn augment_widest_path(
+        &mut self,
+        source: &Node,
+        sink: &Node,
+        max_distance: Option<u64>,
+        flow: U256,
+        flow_distribution: &mut HashMap<Node, HashMap<Node, U256>>,
+    ) -> Option<U256> {
+        // Dijkstra's algorithm with (width, distance) as label instead of the distance.
+        let mut labels: HashMap<Node, (U256, usize)> = HashMap::new();
+        let mut parents: HashMap<Node, Node> = HashMap::new();
+        let mut finished = HashSet::new();
+        let mut queue = BinaryHeap::new();
+        labels.insert(source.clone(), (flow, 0));
+        queue.push((flow, Reverse(0), Reverse(source.clone())));
+
+        while let Some((width, Reverse(distance), Reverse(current))) = queue.pop() {
+            if &current == sink {
+                self.augment(&parents, sink, width, flow_distribution);
+                return Some(width);
+            }
+            if !finished.insert(current.clone()) {
+                continue;
+            }
+            if max_distance.is_some_and(|max_dist| distance >= max_dist as usize) {
+                continue;
+            }
+            for (neighbor, capacity) in self.outgoing_edges_sorted_by_capacity(&current) {
+                let label = (min(width, capacity), distance + 1);
+                let is_better = labels
+                    .get(&neighbor)
+                    .is_none_or(|(w, d)| label.0 > *w || (label.0 == *w && label.1 < *d));
+                if !finished.contains(&neighbor) && is_better {
+                    labels.insert(neighbor.clone(), label);
+                    parents.insert(neighbor.clone(), current.clone());
+                    queue.push((label.0, Reverse(label.1), Reverse(neighbor)));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Pushes `flow` along the path to `sink` given by `parents`
+    /// and updates the residual network.
+    fn augment(
+        &mut self,
+        parents: &HashMap<Node, Node>,
+        sink: &Node,
+        flow: U256,
+        flow_distribution: &mut HashMap<Node, HashMap<Node, U256>>,
+    ) {
+        let mut node = sink;
+        while let Some(parent) = parents.get(node) {
+            self.adjust_capacity(parent, node, -flow);
+            self.adjust_capacity(node, parent, flow);
+            add_flow(flow_distribution, parent, node, flow);
+            node = parent;
+        }
+    }
+
     // // recursive implementation
     // pub fn dfs_search_blocking_flow(
     //     &mut self,
@@ -196,15 +386,9 @@ impl<'a> Adjacencies<'a> {
     //     None
     // }
 
-    #[allow(dead_code)]
     pub fn outgoing_edges_sorted_by_capacity(&mut self, from: &Node) -> Vec<(Node, U256)> {
-        let mut adjacencies = self.adjacencies_from(from);
-        if let Some(adjustments) = self.capacity_adjustments.get(from) {
-            for (node, c) in adjustments {
-                *adjacencies.entry(node.clone()).or_default() += *c;
-            }
-        }
-        let mut result = adjacencies
+        let mut result = self
+            .adjacencies_from(from)
             .into_iter()
             .filter(|(_, cap)| *cap != U256::from(0))
             .collect::<Vec<(Node, U256)>>();
@@ -232,7 +416,76 @@ impl<'a> Adjacencies<'a> {
         }
     }
 
+    /// Returns the edge of the underlying capacity network.
+    pub fn edge(&self, from: &Address, to: &Address, token: &Address) -> Option<Edge> {
+        outgoing(self.edges, self.overlay.as_deref(), from)
+            .find(|e| e.to == *to && e.token == *token && self.is_used(e))
+    }
+
+    /// Returns false if the edge is excluded by `restrict_edges`.
+    fn is_used(&self, edge: &Edge) -> bool {
+        self.edge_mask
+            .as_ref()
+            .is_none_or(|mask| mask.contains(&(edge.from, edge.to, edge.token)))
+    }
+
+    /// Returns the capacity of the edge of the underlying capacity network that can be
+    /// used by the flow, i.e. with the capacity margin and the maximum edge share applied,
+    /// or None if there is no such edge or it is ignored due to the minimum edge capacity.
+    pub fn usable_capacity(&self, from: &Address, to: &Address, token: &Address) -> Option<U256> {
+        self.edge(from, to, token)
+            .map(|edge| {
+                usable_capacity(
+                    self.edges,
+                    &self.capacity_margin,
+                    self.max_edge_share,
+                    &edge,
+                )
+            })
+            .filter(|capacity| *capacity >= self.min_edge_capacity)
+    }
+
+    /// Returns true if `from -> to` is an edge from the restricted source
+    /// to a balance node of a token it is not allowed to spend.
+    fn is_disallowed_first_hop(&self, from: &Node, to: &Node) -> bool {
+        match (&self.source_tokens, from, to) {
+            (Some((source, tokens)), Node::Node(from), Node::BalanceNode(_, token)) => {
+                source == from && !tokens.contains(token)
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the nodes that can be reached from `source` in the residual network within
+    /// `max_distance`. After a max flow computation, this is the source side of a minimum cut.
+    pub fn residual_reachable(
+        &mut self,
+        source: &Node,
+        max_distance: Option<u64>,
+    ) -> HashSet<Node> {
+        let mut reachable = HashSet::from([source.clone()]);
+        let mut queue = VecDeque::from([(source.clone(), 0)]);
+        while let Some((node, distance)) = queue.pop_front() {
+            if max_distance.is_some_and(|max_distance| distance >= max_distance) {
+                continue;
+            }
+            for (neighbor, capacity) in self.adjacencies_from(&node) {
+                if capacity > U256::from(0) && reachable.insert(neighbor.clone()) {
+                    queue.push_back((neighbor, distance + 1));
+                }
+            }
+        }
+        reachable
+    }
+
     pub fn adjacencies_from(&mut self, from: &Node) -> HashMap<Node, U256> {
+        let (edges, margin, share) = (self.edges, self.capacity_margin, self.max_edge_share);
+        let capacity_of = |edge: &Edge| usable_capacity(edges, &margin, share, edge);
+        let mask = &self.edge_mask;
+        let is_used = |edge: &Edge| {
+            mask.as_ref()
+                .is_none_or(|mask| mask.contains(&(edge.from, edge.to, edge.token)))
+        };
         let result = self
             .lazy_adjacencies
             .entry(from.clone())
@@ -241,24 +494,40 @@ impl<'a> Adjacencies<'a> {
                 // Plain edges are (from, to, token) labeled with capacity
                 match from {
                     Node::Node(from) => {
-                        for edge in self.edges.outgoing(from) {
+                        let allowed_tokens = match &self.source_tokens {
+                            Some((source, tokens)) if source == from => Some(tokens),
+                            _ => None,
+                        };
+                        for edge in
+                            outgoing(self.edges, self.overlay.as_deref(), from).filter(is_used)
+                        {
+                            if allowed_tokens.is_some_and(|tokens| !tokens.contains(&edge.token)) {
+                                continue;
+                            }
                             // One edge from "from" to "from x token" with a capacity
                             // as the max over all "to" addresses (the balance of the sender)
+                            let capacity = capacity_of(&edge);
                             computed_adjacencies
-                                .entry(balance_node(edge))
+                                .entry(balance_node(&edge))
                                 .and_modify(|c| {
-                                    if edge.capacity > *c {
-                                        *c = edge.capacity;
+                                    if capacity > *c {
+                                        *c = capacity;
                                     }
                                 })
-                                .or_insert(edge.capacity);
+                                .or_insert(capacity);
                         }
                     }
                     Node::BalanceNode(from, token) => {
-                        for edge in self.edges.outgoing(from) {
+                        for edge in
+                            outgoing(self.edges, self.overlay.as_deref(), from).filter(is_used)
+                        {
                             // The actual capacity of the edge / the send limit.
-                            if edge.from == *from && edge.token == *token {
-                                computed_adjacencies.insert(trust_node(edge), edge.capacity);
+                            let capacity = capacity_of(&edge);
+                            if edge.from == *from
+                                && edge.token == *token
+                                && capacity >= self.min_edge_capacity
+                            {
+                                computed_adjacencies.insert(trust_node(&edge), capacity);
                             }
                         }
                     }
@@ -267,34 +536,124 @@ impl<'a> Adjacencies<'a> {
                         // If token is to's token: send back to owner, infinite capacity.
                         // Otherwise, the max of the incoming edges (the trust limit)
                         let mut capacity = U256::from(0);
-                        for edge in self.edges.incoming(to) {
+                        for edge in
+                            incoming(self.edges, self.overlay.as_deref(), to).filter(is_used)
+                        {
                             if edge.token == *token {
                                 if is_return_to_owner {
-                                    capacity += edge.capacity
+                                    capacity += capacity_of(&edge)
                                 } else {
-                                    capacity = max(capacity, edge.capacity)
+                                    capacity = max(capacity, capacity_of(&edge))
                                 }
                             }
                             computed_adjacencies.insert(Node::Node(*to), capacity);
                         }
                     }
+                    Node::SuperSource => {
+                        for (source, capacity) in &self.super_source_edges {
+                            computed_adjacencies.insert(Node::Node(*source), *capacity);
+                        }
+                    }
+                    Node::SuperSink => {}
+                }
+                if let Node::Node(from) = from {
+                    if let Some(capacity) = self.super_sink_edges.get(from) {
+                        computed_adjacencies.insert(Node::SuperSink, *capacity);
+                    }
                 }
                 computed_adjacencies
             })
             .clone();
 
-        // Thinking of capacity adjustment
-        let mut adjusted_result = result.clone();
-        for (neighbor, capacity) in adjusted_result.iter_mut() {
-            if let Some(adjustment) = self
-                .capacity_adjustments
-                .get(from)
-                .and_then(|m| m.get(neighbor))
-            {
-                *capacity += *adjustment;
+        // Apply the capacity adjustments. Adjustments to nodes that are not
+        // adjacent in the original network are the residual (backwards) edges.
+        let mut adjusted_result = result;
+        if let Some(adjustments) = self.capacity_adjustments.get(from) {
+            for (neighbor, adjustment) in adjustments {
+                if self.is_disallowed_first_hop(from, neighbor) {
+                    continue;
+                }
+                *adjusted_result.entry(neighbor.clone()).or_default() += *adjustment;
             }
         }
 
         adjusted_result
     }
 }
+
+/// Returns the capacity of `edge` reduced by the capacity margin and the maximum edge share.
+fn usable_capacity(
+    edges: &EdgeDB,
+    margin: &Option<(CapacityMargin, SystemTime)>,
+    share: Option<(u64, Address)>,
+    edge: &Edge,
+) -> U256 {
+    let capacity = match margin {
+        Some((margin, now)) => margin.apply(edge.capacity, edges.updated_at(edge), *now),
+        None => edge.capacity,
+    };
+    match share {
+        Some((percent, exempt)) if edge.from != exempt => {
+            min(capacity, percent_of(edge.capacity, percent))
+        }
+        _ => capacity,
+    }
+}
+
+/// Returns the edges sent by `from`, with the overlay applied if there is one.
+/// Without an overlay, the edges are not copied into a new vector.
+pub(crate) fn outgoing<'e>(
+    edges: &'e EdgeDB,
+    overlay: Option<&EdgeOverlay>,
+    from: &Address,
+) -> impl Iterator<Item = Edge> + 'e {
+    let changed = overlay.map(|overlay| overlay.outgoing(edges, from));
+    let unchanged = changed.is_none().then(|| edges.outgoing_iter(from));
+    changed
+        .into_iter()
+        .flatten()
+        .chain(unchanged.into_iter().flatten().copied())
+}
+
+/// Returns the edges received by `to`, with the overlay applied if there is one.
+pub(crate) fn incoming<'e>(
+    edges: &'e EdgeDB,
+    overlay: Option<&EdgeOverlay>,
+    to: &Address,
+) -> impl Iterator<Item = Edge> + 'e {
+    let changed = overlay.map(|overlay| overlay.incoming(edges, to));
+    let unchanged = changed.is_none().then(|| edges.incoming_iter(to));
+    changed
+        .into_iter()
+        .flatten()
+        .chain(unchanged.into_iter().flatten().copied())
+}
+
+/// Adds `amount` of flow on the edge `from -> to` to the flow distribution.
+/// If there is flow in the opposite direction (i.e. we are using a residual edge),
+/// that flow is cancelled first.
+fn add_flow(
+    flow_distribution: &mut HashMap<Node, HashMap<Node, U256>>,
+    from: &Node,
+    to: &Node,
+    mut amount: U256,
+) {
+    if let Some(backwards) = flow_distribution
+        .get_mut(to)
+        .and_then(|out| out.get_mut(from))
+    {
+        let cancelled = min(*backwards, amount);
+        *backwards -= cancelled;
+        amount -= cancelled;
+        if *backwards == U256::from(0) {
+            flow_distribution.get_mut(to).unwrap().remove(from);
+        }
+    }
+    if amount != U256::from(0) {
+        *flow_distribution
+            .entry(from.clone())
+            .or_default()
+            .entry(to.clone())
+            .or_default() += amount;
+    }
+}
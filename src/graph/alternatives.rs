@@ -0,0 +1,130 @@
+use crate::graph::error::FlowError;
+use crate::graph::flow::IncrementalFlow;
+use crate::graph::options::FlowOptions;
+use crate::graph::paths::decompose_transfers;
+use crate::types::edge::EdgeDB;
+use crate::types::{Address, Edge, U256};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// One of the solutions returned by `compute_alternatives`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alternative {
+    pub flow: U256,
+    pub transfers: Vec<Edge>,
+    /// The number of transfers on the longest path from the source to the sink.
+    pub hops: usize,
+    /// The addresses other than the source and the sink that receive or send transfers.
+    pub intermediaries: BTreeSet<Address>,
+}
+
+impl Alternative {
+    fn new(source: &Address, sink: &Address, flow: U256, transfers: Vec<Edge>) -> Alternative {
+        let hops = decompose_transfers(source, sink, &transfers)
+            .iter()
+            .map(|path| path.transfers.len())
+            .max()
+            .unwrap_or_default();
+        let intermediaries = transfers
+            .iter()
+            .flat_map(|t| [t.from, t.to])
+            .filter(|address| address != source && address != sink)
+            .collect();
+        Alternative {
+            flow,
+            transfers,
+            hops,
+            intermediaries,
+        }
+    }
+}
+
+/// Computes up to `count` different solutions for a transfer from `source` to `sink`.
+///
+/// The first solution is the one of `compute_flow_with_options`. Each further solution is
+/// computed with all edges (from, to, token) used by the flows of the previous solutions
+/// removed, so no two solutions use the same edge. The search stops early if no flow is left.
+///
+/// An error computing the first solution is returned. If `options.exact` is set, the search
+/// also stops as soon as the requested flow cannot be reached anymore.
+pub fn compute_alternatives(
+    source: &Address,
+    sink: &Address,
+    edges: &EdgeDB,
+    requested_flow: U256,
+    count: usize,
+    options: &FlowOptions,
+) -> Result<Vec<Alternative>, FlowError> {
+    let mut overlay = options.overlay.as_deref().cloned().unwrap_or_default();
+    let mut alternatives: Vec<Alternative> = Vec::new();
+    while alternatives.len() < count {
+        let options = FlowOptions {
+            overlay: (!overlay.is_empty()).then(|| Arc::new(overlay.clone())),
+            ..options.clone()
+        };
+        let mut computation = IncrementalFlow::new(source, sink, edges, requested_flow, &options);
+        computation.extend(options.max_distance);
+        let (flow, transfers) = match computation.transfers() {
+            Ok(result) => result,
+            Err(FlowError::InsufficientFlow { .. }) if !alternatives.is_empty() => break,
+            Err(e) => return Err(e),
+        };
+        if flow == U256::from(0) {
+            break;
+        }
+        // The transfers can bypass intermediaries, so the edges of the flow are removed instead.
+        for edge in computation.pruned_edges() {
+            overlay.set(Edge {
+                capacity: U256::from(0),
+                ..edge
+            });
+        }
+        alternatives.push(Alternative::new(source, sink, flow, transfers));
+    }
+    Ok(alternatives)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{addresses, edge};
+
+    #[test]
+    fn alternatives_use_different_edges() {
+        let (a, b, c, d, t, ..) = addresses();
+        // Three routes from a to d: directly, via b and via c.
+        let edges = EdgeDB::new(vec![
+            edge(a, d, t, 10),
+            edge(a, b, a, 8),
+            edge(b, d, b, 8),
+            edge(a, c, c, 5),
+            edge(c, d, c, 5),
+        ]);
+        let alternatives =
+            compute_alternatives(&a, &d, &edges, U256::from(5), 5, &FlowOptions::default())
+                .unwrap();
+        assert_eq!(alternatives.len(), 3);
+        for alternative in &alternatives {
+            assert_eq!(alternative.flow, U256::from(5));
+        }
+        assert_eq!(alternatives[0].hops, 1);
+        assert!(alternatives[0].intermediaries.is_empty());
+        assert_eq!(alternatives[1].hops, 2);
+        assert_eq!(alternatives[1].intermediaries, BTreeSet::from([b]));
+        // There is no edge a -> d in c's token, so c is not bypassed.
+        assert_eq!(
+            alternatives[2].transfers,
+            vec![edge(a, c, c, 5), edge(c, d, c, 5)]
+        );
+        assert_eq!(alternatives[2].intermediaries, BTreeSet::from([c]));
+
+        // With exact, alternatives that cannot transfer the full amount are not returned.
+        let options = FlowOptions {
+            exact: true,
+            ..Default::default()
+        };
+        let alternatives =
+            compute_alternatives(&a, &d, &edges, U256::from(8), 5, &options).unwrap();
+        assert_eq!(alternatives.len(), 2);
+    }
+}
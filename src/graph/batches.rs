@@ -0,0 +1,142 @@
+use crate::graph::error::FlowError;
+use crate::types::{Address, Edge, U256};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Limits for a single batch of transfers, e.g. one `transferThrough` transaction.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BatchLimits {
+    /// Maximum number of transfer steps in a batch.
+    pub max_steps: Option<usize>,
+    /// Maximum estimated gas of a batch.
+    pub max_gas: Option<u64>,
+    /// Estimated gas per transfer step.
+    pub gas_per_step: u64,
+    /// Estimated gas per batch, independent of the number of steps.
+    pub base_gas: u64,
+}
+
+impl BatchLimits {
+    /// The maximum number of steps in a batch, considering both limits.
+    fn max_batch_size(&self) -> Option<usize> {
+        let by_gas = self.max_gas.map(|max_gas| {
+            let available = max_gas.saturating_sub(self.base_gas);
+            available.checked_div(self.gas_per_step).unwrap_or(u64::MAX) as usize
+        });
+        match (self.max_steps, by_gas) {
+            (Some(steps), Some(gas)) => Some(steps.min(gas)),
+            (steps, gas) => steps.or(gas),
+        }
+    }
+}
+
+/// Splits sorted transfers (see `sort_transfers`) into the smallest number of
+/// consecutive batches within the limits, such that each batch can be executed on its own:
+/// Every address other than `source` receives at least as much as it sends within a batch.
+///
+/// Returns the index ranges of the batches or an error if there is no such split.
+pub fn split_into_batches(
+    source: &Address,
+    transfers: &[Edge],
+    limits: &BatchLimits,
+) -> Result<Vec<Range<usize>>, FlowError> {
+    let max_size = limits.max_batch_size().unwrap_or(transfers.len());
+    // fewest_batches[end]: The smallest number of batches for transfers[..end],
+    // together with the start of the last of these batches.
+    let mut fewest_batches: Vec<Option<(usize, usize)>> = vec![None; transfers.len() + 1];
+    fewest_batches[0] = Some((0, 0));
+    for start in 0..transfers.len() {
+        let Some((count, _)) = fewest_batches[start] else {
+            continue;
+        };
+        let mut balances: HashMap<Address, (U256, U256)> = HashMap::new();
+        let mut negative = 0;
+        for end in start + 1..=(start + max_size).min(transfers.len()) {
+            let transfer = &transfers[end - 1];
+            for (address, received, sent) in [
+                (transfer.from, U256::from(0), transfer.capacity),
+                (transfer.to, transfer.capacity, U256::from(0)),
+            ] {
+                if address == *source {
+                    continue;
+                }
+                let (total_received, total_sent) = balances.entry(address).or_default();
+                let was_negative = total_sent > total_received;
+                *total_received += received;
+                *total_sent += sent;
+                let is_negative = total_sent > total_received;
+                match (was_negative, is_negative) {
+                    (false, true) => negative += 1,
+                    (true, false) => negative -= 1,
+                    _ => {}
+                }
+            }
+            if negative == 0 && fewest_batches[end].is_none_or(|(c, _)| count + 1 < c) {
+                fewest_batches[end] = Some((count + 1, start));
+            }
+        }
+    }
+
+    let mut batches = vec![];
+    let mut end = transfers.len();
+    while end > 0 {
+        let (_, start) = fewest_batches[end].ok_or(FlowError::UnbatchableTransfers)?;
+        batches.push(start..end);
+        end = start;
+    }
+    batches.reverse();
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{addresses, edge};
+
+    #[test]
+    fn batches() {
+        let (a, b, c, d, ..) = addresses();
+        let transfers = vec![
+            edge(a, d, a, 2),
+            edge(a, b, a, 3),
+            edge(b, c, b, 3),
+            edge(c, d, c, 3),
+        ];
+        let limits = |max_steps| BatchLimits {
+            max_steps: Some(max_steps),
+            ..Default::default()
+        };
+        let all = 0..transfers.len();
+        assert_eq!(
+            split_into_batches(&a, &transfers, &BatchLimits::default()),
+            Ok(vec![all])
+        );
+        // b and c cannot send in a batch without receiving first.
+        assert_eq!(
+            split_into_batches(&a, &transfers, &limits(3)),
+            Ok(vec![0..1, 1..4])
+        );
+        assert_eq!(
+            split_into_batches(&a, &transfers, &limits(2)),
+            Err(FlowError::UnbatchableTransfers)
+        );
+        let gas_limits = BatchLimits {
+            max_gas: Some(100_000),
+            gas_per_step: 30_000,
+            base_gas: 21_000,
+            ..Default::default()
+        };
+        assert_eq!(
+            split_into_batches(&a, &transfers, &gas_limits),
+            Err(FlowError::UnbatchableTransfers)
+        );
+        let gas_limits = BatchLimits {
+            max_gas: Some(120_000),
+            ..gas_limits
+        };
+        assert_eq!(
+            split_into_batches(&a, &transfers, &gas_limits),
+            Ok(vec![0..1, 1..4])
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::graph::adjacencies::Adjacencies;
+use crate::graph::flow::dinic_max_flow;
+use crate::graph::Node;
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::{Address, U256};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A cut tree over a set of addresses that answers max flow queries between any
+/// two of them with a single max flow computation per address.
+///
+/// The tree is built with Gusfield's algorithm: Each address is connected to a parent
+/// and the tree edge is labeled with the max flow from the address to its parent.
+/// The max flow between two addresses is the smallest label on the tree path between them.
+///
+/// Gusfield's algorithm is exact for undirected networks. The flow network is directed
+/// (the flows in the two directions between a pair can differ), so for pairs that are
+/// not connected by a tree edge, the result is only an approximation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CutTree {
+    addresses: Vec<Address>,
+    /// Index of the parent of each address, the root (index 0) is its own parent.
+    parents: Vec<usize>,
+    /// Max flow from each address to its parent.
+    flows: Vec<U256>,
+}
+
+impl CutTree {
+    /// Builds the cut tree over the given addresses (duplicates are ignored)
+    /// with `addresses.len() - 1` max flow computations on `edges`
+    /// (with the changes of the overlay applied, if there is one).
+    pub fn new(
+        addresses: &[Address],
+        edges: &EdgeDB,
+        overlay: Option<Arc<EdgeOverlay>>,
+        max_distance: Option<u64>,
+    ) -> CutTree {
+        let mut unique = addresses.to_vec();
+        unique.sort();
+        unique.dedup();
+        let n = unique.len();
+        let mut parents = vec![0; n];
+        let mut flows = vec![U256::from(0); n];
+        for s in 1..n {
+            let t = parents[s];
+            let mut adjacencies = Adjacencies::new(edges);
+            if let Some(overlay) = &overlay {
+                adjacencies.set_overlay(overlay.clone());
+            }
+            let (source, sink) = (Node::Node(unique[s]), Node::Node(unique[t]));
+            flows[s] = dinic_max_flow(
+                &mut adjacencies,
+                &source,
+                &sink,
+                max_distance,
+                &mut HashMap::new(),
+            );
+            let source_side = adjacencies.residual_reachable(&source, max_distance);
+            let in_source_side = |i: usize| source_side.contains(&Node::Node(unique[i]));
+            for (i, parent) in parents.iter_mut().enumerate().skip(s + 1) {
+                if *parent == t && in_source_side(i) {
+                    *parent = s;
+                }
+            }
+            if t != 0 && in_source_side(parents[t]) {
+                parents[s] = parents[t];
+                parents[t] = s;
+                flows.swap(s, t);
+            }
+        }
+        CutTree {
+            addresses: unique,
+            parents,
+            flows,
+        }
+    }
+
+    pub fn addresses(&self) -> &[Address] {
+        &self.addresses
+    }
+
+    /// Returns the (approximate) max flow between `from` and `to`, or None
+    /// if one of them is not part of the tree.
+    pub fn max_flow(&self, from: &Address, to: &Address) -> Option<U256> {
+        let from = self.addresses.binary_search(from).ok()?;
+        let to = self.addresses.binary_search(to).ok()?;
+        if from == to {
+            return Some(U256::MAX);
+        }
+        // Smallest flow on the path from `from` to each of its ancestors.
+        let mut ancestors = HashMap::from([(from, U256::MAX)]);
+        let (mut node, mut smallest) = (from, U256::MAX);
+        while node != 0 {
+            smallest = min(smallest, self.flows[node]);
+            node = self.parents[node];
+            ancestors.insert(node, smallest);
+        }
+        let (mut node, mut smallest) = (to, U256::MAX);
+        loop {
+            if let Some(from_smallest) = ancestors.get(&node) {
+                return Some(min(smallest, *from_smallest));
+            }
+            smallest = min(smallest, self.flows[node]);
+            node = self.parents[node];
+        }
+    }
+
+    /// Returns the tree as JSON: The addresses and the tree edges from
+    /// each address (except the root) to its parent with the max flow.
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            addresses: self.addresses.iter().map(|a| a.to_checksummed_hex()).collect::<Vec<_>>(),
+            edges: (1..self.addresses.len()).map(|i| json::object! {
+                from: self.addresses[i].to_checksummed_hex(),
+                to: self.addresses[self.parents[i]].to_checksummed_hex(),
+                maxFlow: self.flows[i].to_decimal(),
+            }).collect::<Vec<_>>(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::compute_flow;
+    use crate::test_utils::addresses;
+    use crate::types::Edge;
+
+    #[test]
+    fn cut_tree_matches_max_flows() {
+        let (a, b, c, d, e, _) = addresses();
+        // Each edge uses a different token so that the flow network is
+        // symmetric (an undirected network), where the cut tree is exact.
+        let mut edges = vec![];
+        for (x, y, capacity) in [(a, b, 5), (b, c, 3), (a, c, 2), (c, d, 4)] {
+            for (from, to) in [(x, y), (y, x)] {
+                let token = Address::from(format!("0x{:040x}", edges.len() + 1).as_str());
+                edges.push(Edge {
+                    from,
+                    to,
+                    token,
+                    capacity: U256::from(capacity),
+                });
+            }
+        }
+        let edges = EdgeDB::new(edges);
+        let addresses = [a, b, c, d];
+        let tree = CutTree::new(&addresses, &edges, None, None);
+        for from in &addresses {
+            for to in &addresses {
+                if from != to {
+                    let (flow, _) = compute_flow(from, to, &edges, U256::MAX, None, None).unwrap();
+                    assert_eq!(tree.max_flow(from, to), Some(flow));
+                }
+            }
+        }
+        assert_eq!(tree.max_flow(&a, &e), None);
+        assert_eq!(tree.to_json()["edges"].len(), 3);
+    }
+}
@@ -0,0 +1,44 @@
+use crate::types::Edge;
+
+/// Returns for each transfer the indices of the transfers it depends on.
+///
+/// The transfers have to be sorted (see `sort_transfers`). A sender can only
+/// send after it received all its incoming transfers, so a transfer depends on
+/// all earlier transfers to its sender. Transfers that do not depend on each other
+/// (directly or indirectly) can be executed in parallel.
+pub fn transfer_dependencies(transfers: &[Edge]) -> Vec<Vec<usize>> {
+    transfers
+        .iter()
+        .enumerate()
+        .map(|(i, transfer)| {
+            transfers[..i]
+                .iter()
+                .enumerate()
+                .filter(|(_, earlier)| earlier.to == transfer.from)
+                .map(|(j, _)| j)
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{addresses, edge};
+
+    #[test]
+    fn dependencies() {
+        let (a, b, c, d, ..) = addresses();
+        let transfers = vec![
+            edge(a, b, a, 1),
+            edge(a, c, a, 1),
+            edge(c, b, c, 1),
+            edge(b, d, b, 1),
+            edge(c, d, c, 1),
+        ];
+        assert_eq!(
+            transfer_dependencies(&transfers),
+            vec![vec![], vec![], vec![1], vec![0, 2], vec![1]]
+        );
+    }
+}
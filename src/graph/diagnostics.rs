@@ -0,0 +1,472 @@
+use crate::graph::adjacencies::{outgoing, Adjacencies};
+use crate::graph::options::FlowOptions;
+use crate::graph::Node;
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::{Address, Edge, U256};
+use json::JsonValue;
+use std::cmp::min;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+
+/// A reason why no flow can be sent from a source to a sink.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ZeroFlowReason {
+    /// The source and the sink are the same address.
+    SameAddress,
+    /// The address is not part of any edge.
+    UnknownAddress(Address),
+    /// The source has no outgoing edges (not even with zero capacity).
+    NoOutgoingEdges,
+    /// The sink has no incoming edges (not even with zero capacity).
+    NoIncomingEdges,
+    /// There is no trust path from the source to the sink.
+    NoPath,
+    /// The shortest trust path has `hops` transfers, which needs a
+    /// `max_distance` of `3 * hops` flow network levels.
+    NoPathWithinDistance { hops: usize, max_distance: u64 },
+    /// The shortest trust path has more transfers than `max_hops` allows.
+    NoPathWithinHops { hops: usize, max_hops: u64 },
+    /// There are trust paths within the limits, but none of them has capacity
+    /// (e.g. because the edges have zero capacity or share an exhausted balance).
+    NoCapacity,
+    /// There is flow, but not with the tokens the source is allowed to spend.
+    TokensExcluded,
+    /// There is flow, but not over edges with at least `min_transfer_amount`.
+    BelowMinTransferAmount(U256),
+    /// There is flow, but not after subtracting the capacity margin.
+    CapacityMargin,
+    /// There is flow, but not when only this percentage of each edge can be used.
+    MaxEdgeShare(u64),
+    /// There is flow with all options, but not within this number of transfers.
+    MaxTransfers(u64),
+    /// There is flow with each of the options alone, but not with all of them together.
+    FilteredByOptions,
+}
+
+impl ZeroFlowReason {
+    /// A short identifier of the reason, e.g. for JSON output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ZeroFlowReason::SameAddress => "same_address",
+            ZeroFlowReason::UnknownAddress(_) => "unknown_address",
+            ZeroFlowReason::NoOutgoingEdges => "no_outgoing_edges",
+            ZeroFlowReason::NoIncomingEdges => "no_incoming_edges",
+            ZeroFlowReason::NoPath => "no_path",
+            ZeroFlowReason::NoPathWithinDistance { .. } => "no_path_within_distance",
+            ZeroFlowReason::NoPathWithinHops { .. } => "no_path_within_hops",
+            ZeroFlowReason::NoCapacity => "no_capacity",
+            ZeroFlowReason::TokensExcluded => "tokens_excluded",
+            ZeroFlowReason::BelowMinTransferAmount(_) => "below_min_transfer_amount",
+            ZeroFlowReason::CapacityMargin => "capacity_margin",
+            ZeroFlowReason::MaxEdgeShare(_) => "max_edge_share",
+            ZeroFlowReason::MaxTransfers(_) => "max_transfers",
+            ZeroFlowReason::FilteredByOptions => "filtered_by_options",
+        }
+    }
+}
+
+impl Display for ZeroFlowReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ZeroFlowReason::SameAddress => write!(f, "Source and sink are the same address"),
+            ZeroFlowReason::UnknownAddress(address) => {
+                write!(f, "Address {address} is not part of any edge")
+            }
+            ZeroFlowReason::NoOutgoingEdges => write!(f, "The source has no outgoing edges"),
+            ZeroFlowReason::NoIncomingEdges => write!(f, "The sink has no incoming edges"),
+            ZeroFlowReason::NoPath => write!(f, "There is no trust path from source to sink"),
+            ZeroFlowReason::NoPathWithinDistance { hops, max_distance } => write!(
+                f,
+                "The shortest trust path has {hops} hops, which needs a max distance of {} (got {max_distance})",
+                hops * 3
+            ),
+            ZeroFlowReason::NoPathWithinHops { hops, max_hops } => write!(
+                f,
+                "The shortest trust path has {hops} hops, but at most {max_hops} are allowed"
+            ),
+            ZeroFlowReason::NoCapacity => {
+                write!(f, "No trust path within the max distance and hops has capacity")
+            }
+            ZeroFlowReason::TokensExcluded => write!(
+                f,
+                "There is flow, but not with the tokens the source is allowed to spend"
+            ),
+            ZeroFlowReason::BelowMinTransferAmount(amount) => write!(
+                f,
+                "There is flow, but no path whose edges have a capacity of at least {}",
+                amount.to_decimal()
+            ),
+            ZeroFlowReason::CapacityMargin => {
+                write!(f, "There is flow, but none is left after the capacity margin")
+            }
+            ZeroFlowReason::MaxEdgeShare(percent) => write!(
+                f,
+                "There is flow, but none if only {percent}% of each edge can be used"
+            ),
+            ZeroFlowReason::MaxTransfers(max_transfers) => write!(
+                f,
+                "There is flow, but none within {max_transfers} transfers"
+            ),
+            ZeroFlowReason::FilteredByOptions => write!(
+                f,
+                "There is flow with each option alone, but not with all options together"
+            ),
+        }
+    }
+}
+
+/// The reasons why a flow is zero, together with the shortest trust path
+/// (regardless of capacities) from the source to the sink, if there is one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlowDiagnostics {
+    pub reasons: Vec<ZeroFlowReason>,
+    pub shortest_path: Option<Vec<Edge>>,
+}
+
+impl FlowDiagnostics {
+    pub fn to_json(&self) -> JsonValue {
+        json::object! {
+            reasons: self.reasons.iter().map(|reason| json::object! {
+                code: reason.code(),
+                message: reason.to_string(),
+            }).collect::<Vec<_>>(),
+            shortestPath: self.shortest_path.as_ref().map(|path| path.iter().map(|e| json::object! {
+                from: e.from.to_checksummed_hex(),
+                to: e.to.to_checksummed_hex(),
+                token_owner: e.token.to_checksummed_hex(),
+                capacity: e.capacity.to_decimal(),
+            }).collect::<Vec<_>>()),
+        }
+    }
+}
+
+/// Explains why no flow from `source` to `sink` was found with the given options
+/// (as for `compute_flow_with_options`), including the overlay of the options.
+///
+/// Only meant to be called after the flow turned out to be zero: If there is no flow
+/// even without the options that filter edges and capacities, the reason is
+/// `ZeroFlowReason::NoCapacity`. Otherwise the reasons are the options that remove all
+/// flow on their own, `ZeroFlowReason::MaxTransfers` if there is flow with all of them,
+/// or `ZeroFlowReason::FilteredByOptions` if only all of them together remove it.
+pub fn diagnose_zero_flow(
+    source: &Address,
+    sink: &Address,
+    edges: &EdgeDB,
+    options: &FlowOptions,
+) -> FlowDiagnostics {
+    if source == sink {
+        return FlowDiagnostics {
+            reasons: vec![ZeroFlowReason::SameAddress],
+            shortest_path: None,
+        };
+    }
+    let overlay = options.overlay.as_deref();
+    let mut reasons = vec![];
+    let is_known = |address| {
+        !all_outgoing(edges, overlay, address).is_empty()
+            || !all_incoming(edges, overlay, address).is_empty()
+    };
+    if !is_known(source) {
+        reasons.push(ZeroFlowReason::UnknownAddress(*source));
+    } else if all_outgoing(edges, overlay, source).is_empty() {
+        reasons.push(ZeroFlowReason::NoOutgoingEdges);
+    }
+    if !is_known(sink) {
+        reasons.push(ZeroFlowReason::UnknownAddress(*sink));
+    } else if all_incoming(edges, overlay, sink).is_empty() {
+        reasons.push(ZeroFlowReason::NoIncomingEdges);
+    }
+    if !reasons.is_empty() {
+        return FlowDiagnostics {
+            reasons,
+            shortest_path: None,
+        };
+    }
+
+    let shortest_path = shortest_trust_path(source, sink, edges, overlay);
+    let Some(path) = &shortest_path else {
+        return FlowDiagnostics {
+            reasons: vec![ZeroFlowReason::NoPath],
+            shortest_path,
+        };
+    };
+    let hops = path.len();
+    match (options.max_distance, options.max_hops) {
+        (Some(max_distance), _) if hops as u64 * 3 > max_distance => {
+            reasons.push(ZeroFlowReason::NoPathWithinDistance { hops, max_distance })
+        }
+        (_, Some(max_hops)) if hops as u64 > max_hops => {
+            reasons.push(ZeroFlowReason::NoPathWithinHops { hops, max_hops })
+        }
+        _ => reasons = filtering_options(source, sink, edges, options),
+    }
+    FlowDiagnostics {
+        reasons,
+        shortest_path,
+    }
+}
+
+/// Returns `ZeroFlowReason::NoCapacity` if there is no flow within the limits of `options`
+/// with the raw capacities (and the overlay), otherwise the options that remove all flow.
+fn filtering_options(
+    source: &Address,
+    sink: &Address,
+    edges: &EdgeDB,
+    options: &FlowOptions,
+) -> Vec<ZeroFlowReason> {
+    // Each hop is three levels in the flow network.
+    let max_distance = options
+        .max_hops
+        .map(|hops| hops * 3)
+        .into_iter()
+        .chain(options.max_distance)
+        .reduce(min);
+    let raw = FlowOptions {
+        overlay: options.overlay.clone(),
+        ..Default::default()
+    };
+    let has_flow = |options: &FlowOptions| {
+        let mut adjacencies = Adjacencies::with_options(edges, source, options);
+        let preferences = &options.token_preferences;
+        if preferences.spend_tokens.is_some() || !preferences.avoid_tokens.is_empty() {
+            let allowed = outgoing(edges, options.overlay.as_deref(), source)
+                .map(|e| e.token)
+                .filter(|token| preferences.allows(token))
+                .collect::<BTreeSet<_>>();
+            adjacencies.restrict_source_tokens(source, Some(allowed));
+        }
+        adjacencies
+            .bfs_level_graph(&Node::Node(*source), &Node::Node(*sink), max_distance)
+            .is_some()
+    };
+    if !has_flow(&raw) {
+        return vec![ZeroFlowReason::NoCapacity];
+    }
+    let mut reasons = vec![];
+    if !has_flow(&FlowOptions {
+        token_preferences: options.token_preferences.clone(),
+        ..raw.clone()
+    }) {
+        reasons.push(ZeroFlowReason::TokensExcluded);
+    }
+    if let Some(amount) = options.min_transfer_amount {
+        if !has_flow(&FlowOptions {
+            min_transfer_amount: Some(amount),
+            ..raw.clone()
+        }) {
+            reasons.push(ZeroFlowReason::BelowMinTransferAmount(amount));
+        }
+    }
+    if options.capacity_margin.is_some()
+        && !has_flow(&FlowOptions {
+            capacity_margin: options.capacity_margin,
+            ..raw.clone()
+        })
+    {
+        reasons.push(ZeroFlowReason::CapacityMargin);
+    }
+    if let Some(percent) = options.max_edge_share {
+        if !has_flow(&FlowOptions {
+            max_edge_share: Some(percent),
+            ..raw.clone()
+        }) {
+            reasons.push(ZeroFlowReason::MaxEdgeShare(percent));
+        }
+    }
+    if reasons.is_empty() {
+        reasons.push(match options.max_transfers {
+            Some(max_transfers) if has_flow(options) => ZeroFlowReason::MaxTransfers(max_transfers),
+            _ => ZeroFlowReason::FilteredByOptions,
+        });
+    }
+    reasons
+}
+
+/// Like `EdgeDB::all_outgoing`, with the overlay applied if there is one.
+fn all_outgoing(edges: &EdgeDB, overlay: Option<&EdgeOverlay>, from: &Address) -> Vec<Edge> {
+    match overlay {
+        Some(overlay) => overlay.all_outgoing(edges, from),
+        None => edges.all_outgoing(from).into_iter().copied().collect(),
+    }
+}
+
+/// Like `EdgeDB::all_incoming`, with the overlay applied if there is one.
+fn all_incoming(edges: &EdgeDB, overlay: Option<&EdgeOverlay>, to: &Address) -> Vec<Edge> {
+    match overlay {
+        Some(overlay) => overlay.all_incoming(edges, to),
+        None => edges.all_incoming(to).into_iter().copied().collect(),
+    }
+}
+
+/// Returns the path with the fewest edges from `source` to `sink`, including
+/// edges with zero capacity.
+fn shortest_trust_path(
+    source: &Address,
+    sink: &Address,
+    edges: &EdgeDB,
+    overlay: Option<&EdgeOverlay>,
+) -> Option<Vec<Edge>> {
+    let mut parent_edges: HashMap<Address, Edge> = HashMap::new();
+    let mut queue = VecDeque::from([*source]);
+    while let Some(address) = queue.pop_front() {
+        if address == *sink {
+            let mut path = vec![];
+            let mut node = *sink;
+            while node != *source {
+                let edge = parent_edges[&node];
+                path.push(edge);
+                node = edge.from;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for edge in all_outgoing(edges, overlay, &address) {
+            if edge.to == *source {
+                continue;
+            }
+            if let Entry::Vacant(entry) = parent_edges.entry(edge.to) {
+                entry.insert(edge);
+                queue.push_back(edge.to);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::options::{CapacityMargin, TokenPreferences};
+    use crate::test_utils::{addresses, edge};
+    use std::sync::Arc;
+
+    #[test]
+    fn zero_flow_reasons() {
+        let (a, b, c, d, e, ..) = addresses();
+        // a -> b -> c has no capacity on the second hop, d only sends to a.
+        let edges = EdgeDB::new(vec![edge(a, b, a, 5), edge(b, c, b, 0), edge(d, a, d, 3)]);
+        let reasons = |source, sink, max_distance| {
+            let options = FlowOptions {
+                max_distance,
+                ..Default::default()
+            };
+            diagnose_zero_flow(&source, &sink, &edges, &options).reasons
+        };
+        assert_eq!(reasons(a, a, None), vec![ZeroFlowReason::SameAddress]);
+        assert_eq!(reasons(e, a, None), vec![ZeroFlowReason::UnknownAddress(e)]);
+        assert_eq!(
+            reasons(c, d, None),
+            vec![
+                ZeroFlowReason::NoOutgoingEdges,
+                ZeroFlowReason::NoIncomingEdges
+            ]
+        );
+        assert_eq!(reasons(b, d, None), vec![ZeroFlowReason::NoIncomingEdges]);
+        assert_eq!(reasons(c, a, None), vec![ZeroFlowReason::NoOutgoingEdges]);
+        assert_eq!(reasons(b, a, None), vec![ZeroFlowReason::NoPath]);
+        assert_eq!(
+            reasons(a, c, Some(3)),
+            vec![ZeroFlowReason::NoPathWithinDistance {
+                hops: 2,
+                max_distance: 3
+            }]
+        );
+        let diagnostics = diagnose_zero_flow(&d, &c, &edges, &FlowOptions::default());
+        assert_eq!(diagnostics.reasons, vec![ZeroFlowReason::NoCapacity]);
+        assert_eq!(
+            diagnostics.shortest_path,
+            Some(vec![edge(d, a, d, 3), edge(a, b, a, 5), edge(b, c, b, 0)])
+        );
+        assert_eq!(diagnostics.to_json()["reasons"][0]["code"], "no_capacity");
+    }
+
+    #[test]
+    fn zero_flow_with_overlay() {
+        let (a, b, c, ..) = addresses();
+        let edges = EdgeDB::new(vec![edge(a, b, a, 5), edge(b, c, b, 5), edge(a, c, a, 0)]);
+        // Removing a -> b leaves only the zero capacity edge a -> c.
+        let options = FlowOptions {
+            overlay: Some(Arc::new(EdgeOverlay::new(vec![edge(a, b, a, 0)]))),
+            ..Default::default()
+        };
+        let diagnostics = diagnose_zero_flow(&a, &c, &edges, &options);
+        assert_eq!(diagnostics.reasons, vec![ZeroFlowReason::NoCapacity]);
+        assert_eq!(diagnostics.shortest_path, Some(vec![edge(a, c, a, 0)]));
+        // Removing b -> c as well leaves c without any edges.
+        let options = FlowOptions {
+            overlay: Some(Arc::new(EdgeOverlay::new(vec![
+                edge(a, c, a, 0),
+                edge(b, c, b, 0),
+            ]))),
+            ..Default::default()
+        };
+        assert_eq!(
+            diagnose_zero_flow(&a, &c, &edges, &options).reasons,
+            vec![ZeroFlowReason::UnknownAddress(c)]
+        );
+    }
+
+    #[test]
+    fn zero_flow_filtered_by_options() {
+        let (a, b, c, ..) = addresses();
+        let edges = EdgeDB::new(vec![edge(a, b, a, 10), edge(b, c, b, 10)]);
+        let reasons = |options: FlowOptions| diagnose_zero_flow(&a, &c, &edges, &options).reasons;
+        assert_eq!(
+            reasons(FlowOptions {
+                max_hops: Some(1),
+                ..Default::default()
+            }),
+            vec![ZeroFlowReason::NoPathWithinHops {
+                hops: 2,
+                max_hops: 1
+            }]
+        );
+        assert_eq!(
+            reasons(FlowOptions {
+                token_preferences: TokenPreferences {
+                    avoid_tokens: BTreeSet::from([a]),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            vec![ZeroFlowReason::TokensExcluded]
+        );
+        assert_eq!(
+            reasons(FlowOptions {
+                min_transfer_amount: Some(U256::from(11)),
+                capacity_margin: Some(CapacityMargin {
+                    percent: 100,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            vec![
+                ZeroFlowReason::BelowMinTransferAmount(U256::from(11)),
+                ZeroFlowReason::CapacityMargin
+            ]
+        );
+        assert_eq!(
+            reasons(FlowOptions {
+                min_transfer_amount: Some(U256::from(6)),
+                max_edge_share: Some(50),
+                ..Default::default()
+            }),
+            vec![ZeroFlowReason::FilteredByOptions]
+        );
+        assert_eq!(
+            reasons(FlowOptions {
+                max_edge_share: Some(0),
+                ..Default::default()
+            }),
+            vec![ZeroFlowReason::MaxEdgeShare(0)]
+        );
+        assert_eq!(
+            reasons(FlowOptions {
+                max_transfers: Some(1),
+                ..Default::default()
+            }),
+            vec![ZeroFlowReason::MaxTransfers(1)]
+        );
+    }
+}
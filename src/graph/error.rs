@@ -0,0 +1,53 @@
+use crate::graph::verify::TransferViolation;
+use crate::types::{Address, U256};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlowError {
+    /// The transfers cannot be put into an order where every sender
+    /// receives all its incoming transfers before it sends.
+    /// Contains the number of transfers that could not be ordered.
+    UnorderableTransfers(usize),
+    /// The transfers cannot be split into batches within the limits
+    /// such that every batch can be executed on its own.
+    UnbatchableTransfers,
+    /// The exact amount was requested, but only `available` can be transferred.
+    InsufficientFlow { requested: U256, available: U256 },
+    /// The computed transfers do not pass `verify_transfers`.
+    InvalidTransfers(Box<TransferViolation>),
+    /// The address is both one of the sources and one of the sinks of a multi-source flow.
+    SourceIsSink(Address),
+}
+
+impl Error for FlowError {}
+
+impl Display for FlowError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlowError::UnorderableTransfers(count) => write!(
+                f,
+                "Error: {count} transfers cannot be ordered because they wait for each other"
+            ),
+            FlowError::UnbatchableTransfers => write!(
+                f,
+                "Error: The transfers cannot be split into valid batches within the limits"
+            ),
+            FlowError::InsufficientFlow {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Error: Requested {} but only {} can be transferred",
+                requested.to_decimal(),
+                available.to_decimal()
+            ),
+            FlowError::InvalidTransfers(violation) => {
+                write!(f, "Error: The computed transfers are invalid: {violation}")
+            }
+            FlowError::SourceIsSink(address) => {
+                write!(f, "Error: {address} is both a source and a sink")
+            }
+        }
+    }
+}
@@ -1,16 +1,22 @@
 use crate::graph::adjacencies::Adjacencies;
+use crate::graph::error::FlowError;
+use crate::graph::hop_limit::HopLimitedFlow;
+use crate::graph::optimize::{optimize_transfers, OptimizationReport};
+use crate::graph::options::{FlowOptions, Objective};
+use crate::graph::paths::decompose_transfers_with_max_hops;
+use crate::graph::transfer_limit::{compute_flow_with_transfer_limit, DEFAULT_TIME_BUDGET};
 use crate::graph::{as_trust_node, Node};
 use crate::types::edge::EdgeDB;
 use crate::types::{Address, Edge, U256};
-use std::cmp::min;
-use std::collections::{BTreeMap, HashSet};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::collections::{HashMap, VecDeque};
 use std::fmt::Write;
 
 /// Computes the maximum flow between a source and a sink using Dinic's algorithm.
 ///
 /// This function also considers optional constraints such as maximum distance and maximum number of transfers.
-/// After computing the flow, it extracts, simplifies, and sorts the transfers.
+/// After computing the flow, it extracts, optimizes, and sorts the transfers.
 ///
 /// # Arguments
 ///
@@ -19,13 +25,16 @@ use std::fmt::Write;
 /// * `edges` - The EdgeDB containing the edges of the flow network.
 /// * `requested_flow` - The requested flow value.
 /// * `max_distance` - An optional maximum distance constraint.
-/// * `max_transfers` - An optional maximum number of transfers constraint.
+/// * `max_transfers` - An optional maximum number of transfers constraint, enforced
+///   by searching for the largest flow within the limit (see `compute_flow_with_options`).
 ///
 /// # Returns
 ///
 /// A tuple containing:
 /// * The computed flow value.
 /// * A vector of sorted edges representing the transfers.
+///
+/// Or an error if the transfers cannot be ordered.
 pub fn compute_flow(
     source: &Address,
     sink: &Address,
@@ -33,47 +42,388 @@ pub fn compute_flow(
     requested_flow: U256,
     max_distance: Option<u64>,
     max_transfers: Option<u64>,
-) -> (U256, Vec<Edge>) {
-    let mut adjacencies = Adjacencies::new(edges);
+) -> Result<(U256, Vec<Edge>), FlowError> {
+    compute_flow_with_options(
+        source,
+        sink,
+        edges,
+        requested_flow,
+        &FlowOptions {
+            max_distance,
+            max_transfers,
+            ..Default::default()
+        },
+    )
+}
+
+/// Computes the maximum flow between a source and a sink like `compute_flow`,
+/// but takes all optional constraints as `FlowOptions`.
+///
+/// If the options contain token preferences, the flow is computed in stages:
+/// The source first only spends its preferred tokens and only
+/// if that does not suffice to reach `requested_flow`, the other allowed tokens are added.
+///
+/// With `max_transfers`, the result is the largest flow within that many transfers found by
+/// `compute_flow_with_transfer_limit` within `DEFAULT_TIME_BUDGET`.
+pub fn compute_flow_with_options(
+    source: &Address,
+    sink: &Address,
+    edges: &EdgeDB,
+    requested_flow: U256,
+    options: &FlowOptions,
+) -> Result<(U256, Vec<Edge>), FlowError> {
+    if let Some(max_transfers) = options.max_transfers {
+        let limited = compute_flow_with_transfer_limit(
+            source,
+            sink,
+            edges,
+            requested_flow,
+            max_transfers,
+            DEFAULT_TIME_BUDGET,
+            options,
+        )?;
+        return Ok((limited.flow, limited.transfers));
+    }
+    let mut computation = IncrementalFlow::new(source, sink, edges, requested_flow, options);
+    computation.extend(options.max_distance);
+    computation.transfers()
+}
+
+/// A flow computation that can be continued with a larger maximum distance.
+///
+/// The residual network and the flow found in previous rounds are kept,
+/// so each round only searches for additional augmenting paths.
+pub struct IncrementalFlow<'a> {
+    source: Address,
+    sink: Address,
+    requested_flow: U256,
+    options: FlowOptions,
+    adjacencies: Adjacencies<'a>,
+    /// The sets of tokens the source can spend, see `TokenPreferences::spending_stages`.
+    spending_stages: Vec<Option<BTreeSet<Address>>>,
+    /// The index of the last stage that was used.
+    last_stage: usize,
+    flow: U256,
+    flow_distribution: HashMap<Node, HashMap<Node, U256>>,
+    /// The flow as paths within the hop limit, only used if `options.max_hops` is set.
+    hop_limited_flow: HopLimitedFlow,
+}
 
-    // Use Dinic's algorithm to compute the max flow and get the flow distribution
-    let (mut flow, flow_distribution) = dinic_max_flow(
-        &mut adjacencies,
-        Node::Node(*source),
-        Node::Node(*sink),
-        max_distance,
-    );
+impl<'a> IncrementalFlow<'a> {
+    pub fn new(
+        source: &Address,
+        sink: &Address,
+        edges: &'a EdgeDB,
+        requested_flow: U256,
+        options: &FlowOptions,
+    ) -> Self {
+        IncrementalFlow {
+            source: *source,
+            sink: *sink,
+            requested_flow,
+            options: options.clone(),
+            adjacencies: Adjacencies::with_options(edges, source, options),
+            spending_stages: options.token_preferences.spending_stages(
+                source,
+                edges,
+                options.overlay.as_deref(),
+            ),
+            last_stage: 0,
+            flow: U256::from(0),
+            flow_distribution: HashMap::new(),
+            hop_limited_flow: HopLimitedFlow::default(),
+        }
+    }
 
-    // Update used_edges based on the flow distribution
-    let used_edges = flow_distribution;
+    /// Only uses the given edges (from, to, token) of the capacity network.
+    /// Has to be called before the flow is extended.
+    pub fn restrict_edges(&mut self, edges: HashSet<(Address, Address, Address)>) {
+        self.adjacencies.restrict_edges(Some(edges));
+    }
 
-    println!("Max flow: {}", flow.to_decimal());
+    /// The maximum flow found so far (before pruning to the requested flow).
+    pub fn flow(&self) -> U256 {
+        self.flow
+    }
 
-    if flow > requested_flow {
-        let still_to_prune =
-            prune_flow(source, sink, flow - requested_flow, &mut used_edges.clone());
-        flow = requested_flow + still_to_prune;
+    /// Returns the edges of the network that carry flow (before pruning),
+    /// with their full capacity.
+    pub fn used_edges(&self) -> Vec<Edge> {
+        let mut used = self
+            .flow_distribution
+            .iter()
+            .filter_map(|(from, out)| match from {
+                Node::BalanceNode(from, token) => Some((from, token, out)),
+                _ => None,
+            })
+            .flat_map(|(from, token, out)| {
+                out.iter()
+                    .filter(|(_, flow)| **flow != U256::from(0))
+                    .filter_map(move |(to, _)| match to {
+                        Node::TrustNode(to, _) => self.adjacencies.edge(from, to, token),
+                        _ => None,
+                    })
+            })
+            .collect::<Vec<_>>();
+        used.sort();
+        used
     }
 
-    if let Some(max_transfers) = max_transfers {
-        let lost = reduce_transfers(max_transfers * 3, &mut used_edges.clone());
-        println!(
-            "Capacity lost by transfer count reduction: {}",
-            lost.to_decimal_fraction()
-        );
-        flow -= lost;
+    /// Returns true if the requested flow has been reached.
+    pub fn is_complete(&self) -> bool {
+        self.flow >= self.requested_flow
+    }
+
+    /// Searches for additional flow using paths of at most `max_distance`
+    /// and returns the total flow found so far.
+    pub fn extend(&mut self, max_distance: Option<u64>) -> U256 {
+        for (stage, tokens) in self.spending_stages.iter().enumerate() {
+            if self.is_complete() {
+                break;
+            }
+            self.adjacencies
+                .restrict_source_tokens(&self.source, tokens.clone());
+            let (source, sink) = (Node::Node(self.source), Node::Node(self.sink));
+            self.flow += match (self.options.max_hops, self.options.objective) {
+                (Some(max_hops), objective) => {
+                    // Each hop is three levels in the flow network.
+                    let max_hops = max_distance.map_or(max_hops, |d| min(max_hops, d / 3));
+                    let limit = match objective {
+                        Objective::MaximizeFlow => U256::MAX,
+                        Objective::MinimizeTransfers => self.requested_flow - self.flow,
+                    };
+                    let adjacencies = &mut self.adjacencies;
+                    let flow =
+                        self.hop_limited_flow
+                            .augment(&source, &sink, max_hops, limit, |node| {
+                                adjacencies.adjacencies_from(node)
+                            });
+                    self.flow_distribution = self.hop_limited_flow.used_edges().clone();
+                    flow
+                }
+                // Use Dinic's algorithm to compute the max flow and get the flow distribution
+                (None, Objective::MaximizeFlow) => dinic_max_flow(
+                    &mut self.adjacencies,
+                    &source,
+                    &sink,
+                    max_distance,
+                    &mut self.flow_distribution,
+                ),
+                (None, Objective::MinimizeTransfers) => widest_paths_flow(
+                    &mut self.adjacencies,
+                    &source,
+                    &sink,
+                    max_distance,
+                    self.requested_flow - self.flow,
+                    &mut self.flow_distribution,
+                ),
+            };
+            if self.flow != U256::from(0) {
+                self.last_stage = max(self.last_stage, stage);
+            }
+        }
+        println!("Max flow: {}", self.flow.to_decimal());
+        self.flow
+    }
+
+    /// Returns the flow found so far, pruned to the requested flow and reduced
+    /// to the maximum number of transfers, together with the sorted transfers.
+    pub fn transfers(&self) -> Result<(U256, Vec<Edge>), FlowError> {
+        let (flow, transfers, _) = self.transfers_with_report()?;
+        Ok((flow, transfers))
+    }
+
+    /// Like `transfers`, but also returns what the optimization of the transfers saved.
+    pub fn transfers_with_report(
+        &self,
+    ) -> Result<(U256, Vec<Edge>, OptimizationReport), FlowError> {
+        let (source, sink) = (&self.source, &self.sink);
+        let (mut flow, mut used_edges) = self.pruned_flow();
+        let min_amount = self.options.min_transfer_amount.unwrap_or_default();
+        let capacity = |from: &Address, to: &Address, token: &Address| {
+            self.adjacencies.usable_capacity(from, to, token)
+        };
+        let (mut transfers, mut report) = transfers_from_flow(
+            source,
+            sink,
+            &flow,
+            used_edges.clone(),
+            min_amount,
+            capacity,
+        )?;
+
+        if let Some(max_hops) = self.options.max_hops {
+            let mut lost;
+            (transfers, lost) = limit_path_lengths(source, sink, transfers, max_hops)?;
+            if lost != U256::from(0) {
+                // Merging transfers can make paths longer, so try again without merging.
+                cancel_cycles(&mut used_edges);
+                let unmerged = sort_transfers(extract_transfers(source, sink, &flow, used_edges))?;
+                let steps = unmerged.len();
+                let (unmerged, unmerged_lost) =
+                    limit_path_lengths(source, sink, unmerged, max_hops)?;
+                if unmerged_lost < lost {
+                    (transfers, lost) = (unmerged, unmerged_lost);
+                    report = OptimizationReport {
+                        steps_before: steps,
+                        steps_after: steps,
+                        ..Default::default()
+                    };
+                }
+            }
+            flow -= lost;
+        }
+
+        if self.options.exact && flow != self.requested_flow {
+            return Err(FlowError::InsufficientFlow {
+                requested: self.requested_flow,
+                available: flow,
+            });
+        }
+
+        Ok((flow, transfers, report))
+    }
+
+    /// Returns the edges of the capacity network that carry flow after pruning the
+    /// flow like `transfers` does, with the flow on each edge as capacity.
+    /// Unlike the transfers, these are not merged into transfers that bypass intermediaries.
+    pub fn pruned_edges(&self) -> Vec<Edge> {
+        let (_, used_edges) = self.pruned_flow();
+        let mut edges = used_edges
+            .iter()
+            .filter_map(|(from, out)| match from {
+                Node::BalanceNode(from, token) => Some((from, token, out)),
+                _ => None,
+            })
+            .flat_map(|(from, token, out)| {
+                out.iter().filter_map(move |(to, flow)| match to {
+                    Node::TrustNode(to, _) if *flow != U256::from(0) => Some(Edge {
+                        from: *from,
+                        to: *to,
+                        token: *token,
+                        capacity: *flow,
+                    }),
+                    _ => None,
+                })
+            })
+            .collect::<Vec<_>>();
+        edges.sort();
+        edges
+    }
+
+    /// Prunes the flow found so far to the requested flow, the maximum number
+    /// of transfers and the minimum transfer amount.
+    fn pruned_flow(&self) -> (U256, HashMap<Node, HashMap<Node, U256>>) {
+        let (source, sink) = (&self.source, &self.sink);
+        let mut flow = self.flow;
+        let preferred_tokens = match self.last_stage {
+            0 => None,
+            stage => self.spending_stages[stage - 1].as_ref(),
+        };
+        let mut used_edges = if self.options.max_hops.is_some() {
+            // Prune whole paths, so that the remaining paths are still within the hop limit.
+            let mut paths = self.hop_limited_flow.clone();
+            if flow > self.requested_flow {
+                let still_to_prune = paths.prune(flow - self.requested_flow, preferred_tokens);
+                flow = self.requested_flow + still_to_prune;
+            }
+            paths.used_edges().clone()
+        } else {
+            // Update used_edges based on the flow distribution
+            let mut used_edges = self.flow_distribution.clone();
+            cancel_cycles(&mut used_edges);
+            if flow > self.requested_flow {
+                let mut to_prune = flow - self.requested_flow;
+                if let Some(preferred_tokens) = preferred_tokens {
+                    // Remove the flow that uses tokens from the last stage first.
+                    to_prune = prune_first_hop(source, preferred_tokens, to_prune, &mut used_edges);
+                }
+                let still_to_prune = prune_flow(source, sink, to_prune, &mut used_edges);
+                flow = self.requested_flow + still_to_prune;
+            }
+            used_edges
+        };
+
+        if let Some(max_transfers) = self.options.max_transfers {
+            let lost = reduce_transfers(max_transfers * 3, &mut used_edges);
+            println!(
+                "Capacity lost by transfer count reduction: {}",
+                lost.to_decimal_fraction()
+            );
+            flow -= lost;
+        }
+
+        let min_amount = self.options.min_transfer_amount.unwrap_or_default();
+        if min_amount > U256::from(0) {
+            flow -= prune_dust(min_amount, &mut used_edges);
+        }
+
+        (flow, used_edges)
     }
+}
 
-    let transfers = if flow == U256::from(0) {
+/// Turns a flow of value `flow` in the flow network into a list of
+/// transfers, optimizes them and sorts them so that they can be executed in order.
+/// The optimization does not create transfers below `min_amount` and only bypasses
+/// intermediaries on edges whose `capacity` fits the new transfers.
+pub fn transfers_from_flow(
+    source: &Address,
+    sink: &Address,
+    flow: &U256,
+    mut used_edges: HashMap<Node, HashMap<Node, U256>>,
+    min_amount: U256,
+    capacity: impl Fn(&Address, &Address, &Address) -> Option<U256>,
+) -> Result<(Vec<Edge>, OptimizationReport), FlowError> {
+    cancel_cycles(&mut used_edges);
+    let transfers = if *flow == U256::from(0) {
         vec![]
     } else {
-        extract_transfers(source, sink, &flow, used_edges)
+        extract_transfers(source, sink, flow, used_edges)
     };
     println!("Num transfers: {}", transfers.len());
-    let simplified_transfers = simplify_transfers(transfers);
-    println!("After simplification: {}", simplified_transfers.len());
-    let sorted_transfers = sort_transfers(simplified_transfers);
-    (flow, sorted_transfers)
+    let (optimized_transfers, report) = optimize_transfers(transfers, min_amount, capacity);
+    Ok((sort_transfers(optimized_transfers)?, report))
+}
+
+/// Reduces the transfers to the flow that can be sent from `source` to `sink`
+/// on paths of at most `max_hops` transfers (see `decompose_transfers_with_max_hops`).
+///
+/// Returns the remaining sorted transfers and the amount that was removed.
+fn limit_path_lengths(
+    source: &Address,
+    sink: &Address,
+    transfers: Vec<Edge>,
+    max_hops: u64,
+) -> Result<(Vec<Edge>, U256), FlowError> {
+    let flow = transfers
+        .iter()
+        .filter(|transfer| transfer.to == *sink)
+        .fold(U256::from(0), |sum, transfer| sum + transfer.capacity);
+    let paths = decompose_transfers_with_max_hops(source, sink, &transfers, max_hops);
+    let limited_flow = paths
+        .iter()
+        .fold(U256::from(0), |sum, path| sum + path.amount);
+    if limited_flow == flow {
+        return Ok((transfers, U256::from(0)));
+    }
+    let mut merged = BTreeMap::<(Address, Address, Address), U256>::new();
+    for transfer in paths.into_iter().flat_map(|path| path.transfers) {
+        *merged
+            .entry((transfer.from, transfer.to, transfer.token))
+            .or_default() += transfer.capacity;
+    }
+    let transfers = sort_transfers(
+        merged
+            .into_iter()
+            .map(|((from, to, token), capacity)| Edge {
+                from,
+                to,
+                token,
+                capacity,
+            })
+            .collect(),
+    )?;
+    Ok((transfers, flow - limited_flow))
 }
 
 /// Computes the maximum flow in a flow network using Dinic's algorithm.
@@ -81,50 +431,80 @@ pub fn compute_flow(
 /// Dinic's algorithm works by repeatedly constructing level graphs using Breadth-First Search (BFS)
 /// and then searching for blocking flows in these level graphs using Depth-First Search (DFS).
 ///
+/// The search continues on top of the flow already recorded in `adjacencies` and `flow_distribution`,
+/// so it can be called repeatedly with a modified network.
+///
 /// # Arguments
 ///
 /// * `adjacencies` - A mutable reference to the Adjacencies structure representing the flow network.
 /// * `source` - The source node of the flow network.
 /// * `sink` - The sink node of the flow network.
 /// * `max_distance` - An optional maximum distance constraint.
+/// * `flow_distribution` - A mutable reference to a HashMap tracking the flow distribution across edges.
 ///
 /// # Returns
 ///
-/// A tuple containing:
-/// * The maximum flow value.
-/// * A HashMap representing the flow distribution across the edges.
+/// The additional flow value found.
 pub fn dinic_max_flow(
     adjacencies: &mut Adjacencies,
-    source: Node,
-    sink: Node,
+    source: &Node,
+    sink: &Node,
     max_distance: Option<u64>,
-) -> (U256, HashMap<Node, HashMap<Node, U256>>) {
+    flow_distribution: &mut HashMap<Node, HashMap<Node, U256>>,
+) -> U256 {
     let mut max_flow = U256::from(0);
-    let mut flow_distribution: HashMap<Node, HashMap<Node, U256>> = HashMap::new();
-
-    loop {
-        // Step 1: Build the level graph using BFS
-        let levels = match adjacencies.bfs_level_graph(&source, &sink, max_distance) {
-            Some(l) => l,
-            None => break, // If no augmenting path is found, exit the loop
-        };
 
+    // Step 1: Build the level graph using BFS.
+    // If no augmenting path is found, exit the loop.
+    while let Some(levels) = adjacencies.bfs_level_graph(source, sink, max_distance) {
         // Step 2: Search for blocking flows using DFS and update the residual network
         while let Some(flow) = adjacencies.dfs_search_blocking_flow(
-            &source,
-            &sink,
+            source,
+            sink,
             &levels,
             U256::MAX,
-            &mut flow_distribution,
+            flow_distribution,
         ) {
             max_flow += flow;
         }
     }
 
-    (max_flow, flow_distribution)
+    max_flow
 }
 
-pub fn transfers_to_dot(edges: &Vec<Edge>) -> String {
+/// Computes a flow of up to `requested_flow` by repeatedly augmenting along the
+/// path with the largest capacity, preferring short paths if several paths can
+/// cover the rest of the requested flow. This needs fewer transfers than a pruned
+/// maximum flow if `requested_flow` is small compared to the maximum flow.
+///
+/// # Returns
+///
+/// The additional flow value found.
+pub fn widest_paths_flow(
+    adjacencies: &mut Adjacencies,
+    source: &Node,
+    sink: &Node,
+    max_distance: Option<u64>,
+    requested_flow: U256,
+    flow_distribution: &mut HashMap<Node, HashMap<Node, U256>>,
+) -> U256 {
+    let mut total_flow = U256::from(0);
+    while total_flow < requested_flow {
+        match adjacencies.augment_widest_path(
+            source,
+            sink,
+            max_distance,
+            requested_flow - total_flow,
+            flow_distribution,
+        ) {
+            Some(flow) => total_flow += flow,
+            None => break,
+        }
+    }
+    total_flow
+}
+
+pub fn transfers_to_dot(edges: &[Edge]) -> String {
     let mut out = String::new();
     writeln!(out, "digraph transfers {{").expect("");
 
@@ -156,7 +536,6 @@ pub fn transfers_to_dot(edges: &Vec<Edge>) -> String {
     out
 }
 
-#[allow(dead_code)]
 fn trace(parent: HashMap<Node, Node>, source: &Node, sink: &Node) -> Vec<Node> {
     let mut t = vec![sink.clone()];
     let mut node = sink;
@@ -215,25 +594,94 @@ fn prune_flow(
             }
         }
     }
-    // If there is still flow to prune, take the first element in edgesByPathLength
-    // and partially prune its path.
-    if flow_to_prune > U256::from(0) {
-        //println!("Final stage: Still to prune: {}", flow_to_prune);
-        for edges_here in edges_by_path_length.values() {
-            for (a, b) in edges_here {
-                if !used_edges.contains_key(a) || !used_edges[a].contains_key(b) {
-                    continue;
-                }
-                flow_to_prune = prune_edge(used_edges, (a, b), flow_to_prune);
-                if flow_to_prune == U256::from(0) {
-                    return U256::from(0);
-                }
+    // If there is still flow to prune, partially prune paths through the edges
+    // on the longest paths first until exactly the requested flow is left.
+    let (source, sink) = (Node::Node(*source), Node::Node(*sink));
+    for (a, b) in edges_by_path_length.values().flatten() {
+        while flow_to_prune > U256::from(0) {
+            let Some(path) = path_via_edge(&source, &sink, (a, b), used_edges) else {
+                break;
+            };
+            let reduction = path
+                .windows(2)
+                .map(|pair| used_edges[&pair[0]][&pair[1]])
+                .fold(flow_to_prune, min);
+            for pair in path.windows(2) {
+                reduce_capacity(used_edges, (&pair[0], &pair[1]), &reduction);
             }
-            if flow_to_prune == U256::from(0) {
-                return U256::from(0);
+            flow_to_prune -= reduction;
+        }
+    }
+    flow_to_prune
+}
+
+/// Returns a path from `source` to `sink` in the flow that contains the edge `(a, b)`,
+/// or None if the edge does not carry flow anymore.
+fn path_via_edge(
+    source: &Node,
+    sink: &Node,
+    (a, b): (&Node, &Node),
+    used_edges: &HashMap<Node, HashMap<Node, U256>>,
+) -> Option<Vec<Node>> {
+    used_edges.get(a)?.get(b)?;
+    let mut path = find_path(source, a, used_edges)?;
+    path.extend(find_path(b, sink, used_edges)?);
+    Some(path)
+}
+
+/// Returns the nodes of a shortest path from `from` to `to` in the flow.
+fn find_path(
+    from: &Node,
+    to: &Node,
+    used_edges: &HashMap<Node, HashMap<Node, U256>>,
+) -> Option<Vec<Node>> {
+    if from == to {
+        return Some(vec![from.clone()]);
+    }
+    let mut parent = HashMap::<Node, Node>::new();
+    let mut to_process = VecDeque::from([from.clone()]);
+    while let Some(n) = to_process.pop_front() {
+        for next in used_edges.get(&n).into_iter().flat_map(|out| out.keys()) {
+            if *next != *from && !parent.contains_key(next) {
+                parent.insert(next.clone(), n.clone());
+                to_process.push_back(next.clone());
             }
         }
     }
+    if !parent.contains_key(to) {
+        return None;
+    }
+    let mut path = trace(parent, from, to);
+    path.reverse();
+    Some(path)
+}
+
+/// Prunes flow on the edges from the source to its balance nodes
+/// of tokens that are not in `preferred_tokens`.
+/// Returns the remaining flow to prune.
+fn prune_first_hop(
+    source: &Address,
+    preferred_tokens: &BTreeSet<Address>,
+    mut flow_to_prune: U256,
+    used_edges: &mut HashMap<Node, HashMap<Node, U256>>,
+) -> U256 {
+    let source = Node::Node(*source);
+    let mut first_hop = used_edges
+        .get(&source)
+        .map(|out| {
+            out.keys()
+                .filter(|n| !matches!(n, Node::BalanceNode(_, token) if preferred_tokens.contains(token)))
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    first_hop.sort();
+    for balance_node in first_hop {
+        if flow_to_prune == U256::from(0) {
+            break;
+        }
+        flow_to_prune = prune_edge(used_edges, (&source, &balance_node), flow_to_prune);
+    }
     flow_to_prune
 }
 
@@ -258,6 +706,28 @@ fn reduce_transfers(
     reduced_flow
 }
 
+/// Removes all flow on edges between balance and trust nodes (i.e. transfers)
+/// that is below `min_amount`. Returns the flow removed.
+fn prune_dust(min_amount: U256, used_edges: &mut HashMap<Node, HashMap<Node, U256>>) -> U256 {
+    let mut removed = U256::from(0);
+    loop {
+        let dust = used_edges
+            .iter()
+            .filter(|(from, _)| matches!(from, Node::BalanceNode(..)))
+            .flat_map(|(from, out)| {
+                out.iter()
+                    .filter(|(_, flow)| **flow < min_amount)
+                    .map(move |(to, flow)| (*flow, from.clone(), to.clone()))
+            })
+            .min();
+        let Some((flow, from, to)) = dust else {
+            return removed;
+        };
+        prune_edge(used_edges, (&from, &to), flow);
+        removed += flow;
+    }
+}
+
 /// Returns a map from the negative shortest path length to the edge.
 /// The shortest path length is negative so that it is sorted by
 /// longest paths first - those are the ones we want to eliminate first.
@@ -436,6 +906,72 @@ fn prune_path(
     }
 }
 
+/// Removes circulations from the flow distribution. They do not contribute
+/// to the flow, but result in transfers that wait for each other.
+fn cancel_cycles(used_edges: &mut HashMap<Node, HashMap<Node, U256>>) {
+    while let Some(cycle) = find_cycle(used_edges) {
+        let amount = cycle
+            .windows(2)
+            .map(|pair| used_edges[&pair[0]][&pair[1]])
+            .min()
+            .unwrap();
+        for pair in cycle.windows(2) {
+            let outgoing = used_edges.get_mut(&pair[0]).unwrap();
+            let flow = outgoing.get_mut(&pair[1]).unwrap();
+            *flow -= amount;
+            if *flow == U256::from(0) {
+                outgoing.remove(&pair[1]);
+            }
+        }
+    }
+}
+
+/// Returns a cycle of edges with positive flow as a list of nodes,
+/// where the first node is repeated at the end.
+fn find_cycle(used_edges: &HashMap<Node, HashMap<Node, U256>>) -> Option<Vec<Node>> {
+    let successors = |node: &Node| {
+        let mut successors = used_edges
+            .get(node)
+            .into_iter()
+            .flatten()
+            .filter(|(_, flow)| **flow != U256::from(0))
+            .map(|(next, _)| next.clone())
+            .collect::<Vec<_>>();
+        successors.sort();
+        successors
+    };
+    let mut starts = used_edges.keys().cloned().collect::<Vec<_>>();
+    starts.sort();
+    let mut finished = HashSet::new();
+    for start in starts {
+        if finished.contains(&start) {
+            continue;
+        }
+        let mut path = vec![start.clone()];
+        let mut stack = vec![successors(&start)];
+        while let Some(candidates) = stack.last_mut() {
+            match candidates.pop() {
+                Some(next) => {
+                    if let Some(position) = path.iter().position(|node| *node == next) {
+                        let mut cycle = path.split_off(position);
+                        cycle.push(next);
+                        return Some(cycle);
+                    }
+                    if !finished.contains(&next) {
+                        stack.push(successors(&next));
+                        path.push(next);
+                    }
+                }
+                None => {
+                    stack.pop();
+                    finished.insert(path.pop().unwrap());
+                }
+            }
+        }
+    }
+    None
+}
+
 fn extract_transfers(
     source: &Address,
     sink: &Address,
@@ -500,32 +1036,7 @@ fn next_full_capacity_edge(
     panic!();
 }
 
-fn find_pair_to_simplify(transfers: &Vec<Edge>) -> Option<(usize, usize)> {
-    let l = transfers.len();
-    (0..l)
-        .flat_map(move |x| (0..l).map(move |y| (x, y)))
-        .find(|(i, j)| {
-            // We do not need matching capacity, but only then will we save
-            // a transfer.
-            let a = transfers[*i];
-            let b = transfers[*j];
-            *i != *j && a.to == b.from && a.token == b.token && a.capacity == b.capacity
-        })
-}
-
-fn simplify_transfers(mut transfers: Vec<Edge>) -> Vec<Edge> {
-    // We can simplify the transfers:
-    // If we have a transfer (A, B, T) and a transfer (B, C, T),
-    // We can always replace both by (A, C, T).
-
-    while let Some((i, j)) = find_pair_to_simplify(&transfers) {
-        transfers[i].to = transfers[j].to;
-        transfers.remove(j);
-    }
-    transfers
-}
-
-fn sort_transfers(transfers: Vec<Edge>) -> Vec<Edge> {
+fn sort_transfers(transfers: Vec<Edge>) -> Result<Vec<Edge>, FlowError> {
     // We have to sort the transfers to satisfy the following condition:
     // A user can send away their own tokens only after it has received all (trust) transfers.
 
@@ -536,32 +1047,34 @@ fn sort_transfers(transfers: Vec<Edge>) -> Vec<Edge> {
     }
     let mut result = Vec::new();
     let mut queue = transfers.into_iter().collect::<VecDeque<Edge>>();
+    // Number of transfers re-queued since the last one was added to the result.
+    let mut waiting = 0;
     while let Some(e) = queue.pop_front() {
         //println!("queue size: {}", queue.len());
         if *receives_to_wait_for.get(&e.from).unwrap() == 0 {
             *receives_to_wait_for.get_mut(&e.to).unwrap() -= 1;
-            result.push(e)
+            result.push(e);
+            waiting = 0;
         } else {
             queue.push_back(e);
+            waiting += 1;
+            if waiting > queue.len() {
+                return Err(FlowError::UnorderableTransfers(queue.len()));
+            }
         }
     }
-    result
+    Ok(result)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::graph::{verify_transfers, CapacityMargin, Objective, SpendOrder, TokenPreferences};
+    use crate::test_utils::{addresses, edge};
+    use crate::types::edge::EdgeOverlay;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
 
-    fn addresses() -> (Address, Address, Address, Address, Address, Address) {
-        (
-            Address::from("0x11C7e86fF693e9032A0F41711b5581a04b26Be2E"),
-            Address::from("0x22cEDde51198D1773590311E2A340DC06B24cB37"),
-            Address::from("0x33cEDde51198D1773590311E2A340DC06B24cB37"),
-            Address::from("0x447EDde51198D1773590311E2A340DC06B24cB37"),
-            Address::from("0x55c16ce62d26fd51582a646e2e30a3267b1e6d7e"),
-            Address::from("0x66c16ce62d26fd51582a646e2e30a3267b1e6d7e"),
-        )
-    }
     fn build_edges(input: Vec<Edge>) -> EdgeDB {
         EdgeDB::new(input)
     }
@@ -575,7 +1088,7 @@ mod test {
             token: t,
             capacity: U256::from(10),
         }]);
-        let flow = compute_flow(&a, &b, &edges, U256::MAX, None, None);
+        let flow = compute_flow(&a, &b, &edges, U256::MAX, None, None).unwrap();
         assert_eq!(
             flow,
             (
@@ -590,6 +1103,74 @@ mod test {
         );
     }
 
+    #[test]
+    fn requested_flow_is_pruned() {
+        let (a, b, t, ..) = addresses();
+        let edges = build_edges(vec![Edge {
+            from: a,
+            to: b,
+            token: t,
+            capacity: U256::from(10),
+        }]);
+        let flow = compute_flow(&a, &b, &edges, U256::from(4), None, None).unwrap();
+        assert_eq!(
+            flow,
+            (
+                U256::from(4),
+                vec![Edge {
+                    from: a,
+                    to: b,
+                    token: t,
+                    capacity: U256::from(4)
+                }]
+            )
+        );
+    }
+
+    #[test]
+    fn paths_via_shared_intermediary() {
+        let (a, b, c, d, ..) = addresses();
+        // Two paths a -> b -> d and a -> c -> b -> d, where b can only pass on 7.
+        let edges = build_edges(vec![
+            Edge {
+                from: a,
+                to: b,
+                token: a,
+                capacity: U256::from(4),
+            },
+            Edge {
+                from: a,
+                to: c,
+                token: c,
+                capacity: U256::from(5),
+            },
+            Edge {
+                from: c,
+                to: b,
+                token: c,
+                capacity: U256::from(5),
+            },
+            Edge {
+                from: b,
+                to: d,
+                token: b,
+                capacity: U256::from(7),
+            },
+        ]);
+        let (flow, transfers) = compute_flow(&a, &d, &edges, U256::MAX, None, None).unwrap();
+        assert_eq!(flow, U256::from(7));
+        let received_by_d = transfers
+            .iter()
+            .filter(|t| t.to == d)
+            .fold(U256::from(0), |sum, t| sum + t.capacity);
+        assert_eq!(received_by_d, flow);
+        let sent_by_a = transfers
+            .iter()
+            .filter(|t| t.from == a)
+            .fold(U256::from(0), |sum, t| sum + t.capacity);
+        assert_eq!(sent_by_a, flow);
+    }
+
     #[test]
     fn one_hop() {
         let (a, b, c, t1, t2, ..) = addresses();
@@ -607,7 +1188,7 @@ mod test {
                 capacity: U256::from(8),
             },
         ]);
-        let flow = compute_flow(&a, &c, &edges, U256::MAX, None, None);
+        let flow = compute_flow(&a, &c, &edges, U256::MAX, None, None).unwrap();
         assert_eq!(
             flow,
             (
@@ -659,7 +1240,7 @@ mod test {
                 capacity: U256::from(8),
             },
         ]);
-        let mut flow = compute_flow(&a, &d, &edges, U256::MAX, None, None);
+        let mut flow = compute_flow(&a, &d, &edges, U256::MAX, None, None).unwrap();
         flow.1.sort();
         assert_eq!(
             flow,
@@ -693,7 +1274,7 @@ mod test {
                 ]
             )
         );
-        let mut pruned_flow = compute_flow(&a, &d, &edges, U256::from(6), None, None);
+        let mut pruned_flow = compute_flow(&a, &d, &edges, U256::from(6), None, None).unwrap();
         pruned_flow.1.sort();
         assert_eq!(
             pruned_flow,
@@ -751,9 +1332,561 @@ mod test {
                 capacity: U256::from(8),
             },
         ]);
-        let mut flow = compute_flow(&a, &d, &edges, U256::MAX, None, None);
+        let mut flow = compute_flow(&a, &d, &edges, U256::MAX, None, None).unwrap();
         flow.1.sort();
         println!("{:?}", &flow.1);
         assert_eq!(flow.0, U256::from(9));
     }
+
+    #[test]
+    fn token_preferences() {
+        let (a, b, t, ..) = addresses();
+        let edges = build_edges(vec![
+            Edge {
+                from: a,
+                to: b,
+                token: a,
+                capacity: U256::from(10),
+            },
+            Edge {
+                from: a,
+                to: b,
+                token: t,
+                capacity: U256::from(7),
+            },
+        ]);
+        let with_preferences = |token_preferences: TokenPreferences, requested_flow: u128| {
+            compute_flow_with_options(
+                &a,
+                &b,
+                &edges,
+                U256::from(requested_flow),
+                &FlowOptions {
+                    token_preferences,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let own_first = TokenPreferences {
+            order: SpendOrder::OwnTokenFirst,
+            ..Default::default()
+        };
+        let received_first = TokenPreferences {
+            order: SpendOrder::ReceivedTokensFirst,
+            ..Default::default()
+        };
+        let (flow, transfers) = with_preferences(own_first.clone(), 5);
+        assert_eq!(flow, U256::from(5));
+        assert!(transfers.iter().all(|e| e.token == a));
+        let (flow, transfers) = with_preferences(received_first.clone(), 5);
+        assert_eq!(flow, U256::from(5));
+        assert!(transfers.iter().all(|e| e.token == t));
+        // If the preferred tokens do not suffice, only the rest is taken from other tokens.
+        let (flow, mut transfers) = with_preferences(received_first, 9);
+        transfers.sort();
+        assert_eq!(flow, U256::from(9));
+        assert_eq!(
+            transfers,
+            vec![
+                Edge {
+                    from: a,
+                    to: b,
+                    token: a,
+                    capacity: U256::from(2)
+                },
+                Edge {
+                    from: a,
+                    to: b,
+                    token: t,
+                    capacity: U256::from(7)
+                }
+            ]
+        );
+        // Preferences do not limit the flow if more is requested.
+        assert_eq!(with_preferences(own_first, 17).0, U256::from(17));
+
+        let avoid_t = TokenPreferences {
+            avoid_tokens: [t].into(),
+            ..Default::default()
+        };
+        let (flow, transfers) = with_preferences(avoid_t, 17);
+        assert_eq!(flow, U256::from(10));
+        assert!(transfers.iter().all(|e| e.token == a));
+        let only_t = TokenPreferences {
+            spend_tokens: Some([t].into()),
+            ..Default::default()
+        };
+        assert_eq!(with_preferences(only_t, 17).0, U256::from(7));
+    }
+
+    #[test]
+    fn incremental() {
+        let (a, b, c, t, ..) = addresses();
+        let edges = build_edges(vec![
+            Edge {
+                from: a,
+                to: b,
+                token: a,
+                capacity: U256::from(5),
+            },
+            Edge {
+                from: a,
+                to: c,
+                token: t,
+                capacity: U256::from(4),
+            },
+            Edge {
+                from: c,
+                to: b,
+                token: c,
+                capacity: U256::from(4),
+            },
+        ]);
+        let mut computation =
+            IncrementalFlow::new(&a, &b, &edges, U256::from(7), &FlowOptions::default());
+        assert_eq!(computation.extend(Some(1)), U256::from(0));
+        assert_eq!(computation.extend(Some(3)), U256::from(5));
+        assert!(!computation.is_complete());
+        assert_eq!(computation.transfers().unwrap().0, U256::from(5));
+        assert_eq!(computation.extend(None), U256::from(9));
+        assert!(computation.is_complete());
+        let (flow, transfers) = computation.transfers().unwrap();
+        assert_eq!(flow, U256::from(7));
+        assert_eq!(transfers.len(), 3);
+    }
+
+    #[test]
+    fn minimize_transfers() {
+        let (a, b, c, d, t, x) = addresses();
+        // Several direct transfers with small capacity and
+        // a path of two transfers with a large capacity.
+        let edges = build_edges(vec![
+            edge(a, b, t, 3),
+            edge(a, b, x, 3),
+            edge(a, b, d, 3),
+            edge(a, c, a, 10),
+            edge(c, b, c, 10),
+        ]);
+        let with_objective = |objective, requested_flow: u128| {
+            compute_flow_with_options(
+                &a,
+                &b,
+                &edges,
+                U256::from(requested_flow),
+                &FlowOptions {
+                    objective,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        // Pruning the max flow uses two direct transfers and part of the path.
+        let (flow, transfers) = with_objective(Objective::MaximizeFlow, 8);
+        assert_eq!(flow, U256::from(8));
+        assert_eq!(transfers.len(), 3);
+        let (flow, transfers) = with_objective(Objective::MinimizeTransfers, 8);
+        assert_eq!(flow, U256::from(8));
+        assert_eq!(transfers.len(), 2);
+        assert!(transfers.iter().all(|e| e.capacity == U256::from(8)));
+        // Small amounts are sent directly.
+        let (flow, transfers) = with_objective(Objective::MinimizeTransfers, 3);
+        assert_eq!(flow, U256::from(3));
+        assert_eq!(transfers.len(), 1);
+        // Without a requested flow, it is the max flow.
+        assert_eq!(
+            with_objective(Objective::MinimizeTransfers, 100).0,
+            U256::from(19)
+        );
+    }
+
+    #[test]
+    fn cancel_cycles_before_extraction() {
+        let (a, b, c, ..) = addresses();
+        let mut used_edges: HashMap<Node, HashMap<Node, U256>> = HashMap::new();
+        let mut add = |from: Node, to: Node, amount: u128| {
+            used_edges
+                .entry(from)
+                .or_default()
+                .insert(to, U256::from(amount));
+        };
+        // a -> c with 5 and the circulation a -> b -> a with 3.
+        add(Node::Node(a), Node::BalanceNode(a, a), 8);
+        add(Node::BalanceNode(a, a), Node::TrustNode(c, a), 5);
+        add(Node::TrustNode(c, a), Node::Node(c), 5);
+        add(Node::BalanceNode(a, a), Node::TrustNode(b, a), 3);
+        add(Node::TrustNode(b, a), Node::Node(b), 3);
+        add(Node::Node(b), Node::BalanceNode(b, b), 3);
+        add(Node::BalanceNode(b, b), Node::TrustNode(a, b), 3);
+        add(Node::TrustNode(a, b), Node::Node(a), 3);
+
+        let (transfers, _) = transfers_from_flow(
+            &a,
+            &c,
+            &U256::from(5),
+            used_edges,
+            U256::from(0),
+            |_, _, _| None,
+        )
+        .unwrap();
+        assert_eq!(
+            transfers,
+            vec![Edge {
+                from: a,
+                to: c,
+                token: a,
+                capacity: U256::from(5)
+            }]
+        );
+    }
+
+    #[test]
+    fn unorderable_transfers() {
+        let (a, b, ..) = addresses();
+        let transfers = vec![
+            Edge {
+                from: a,
+                to: b,
+                token: a,
+                capacity: U256::from(5),
+            },
+            Edge {
+                from: b,
+                to: a,
+                token: b,
+                capacity: U256::from(5),
+            },
+        ];
+        assert_eq!(
+            sort_transfers(transfers),
+            Err(FlowError::UnorderableTransfers(2))
+        );
+    }
+
+    #[test]
+    fn min_transfer_amount() {
+        let (a, b, t, x, ..) = addresses();
+        let edges = build_edges(vec![edge(a, b, a, 5), edge(a, b, t, 5), edge(a, b, x, 1)]);
+        let with_min = |min_transfer_amount: Option<u128>, requested_flow| {
+            compute_flow_with_options(
+                &a,
+                &b,
+                &edges,
+                requested_flow,
+                &FlowOptions {
+                    min_transfer_amount: min_transfer_amount.map(U256::from),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        assert_eq!(with_min(None, U256::MAX).0, U256::from(11));
+        // The edge with capacity 1 is ignored.
+        let (flow, transfers) = with_min(Some(2), U256::MAX);
+        assert_eq!(flow, U256::from(10));
+        assert_eq!(transfers.len(), 2);
+        // Pruning to 9 leaves a transfer of 4.
+        let (flow, transfers) = with_min(None, U256::from(9));
+        assert_eq!(flow, U256::from(9));
+        assert_eq!(transfers.len(), 2);
+        let (flow, transfers) = with_min(Some(5), U256::from(9));
+        assert_eq!(flow, U256::from(5));
+        assert_eq!(transfers.len(), 1);
+    }
+
+    #[test]
+    fn capacity_margin() {
+        let (a, b, t, ..) = addresses();
+        let mut edges = build_edges(vec![edge(a, b, a, 100), edge(a, b, t, 50)]);
+        let with_margin = |edges: &EdgeDB, min_age| {
+            let margin = CapacityMargin {
+                percent: 10,
+                absolute: U256::from(5),
+                min_age,
+            };
+            compute_flow_with_options(
+                &a,
+                &b,
+                edges,
+                U256::MAX,
+                &FlowOptions {
+                    capacity_margin: Some(margin),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .0
+        };
+        // 100 * 90% - 5 and 50 * 90% - 5
+        assert_eq!(with_margin(&edges, None), U256::from(125));
+        // Only the edge without update time is considered stale.
+        edges.update_at(edge(a, b, t, 50), SystemTime::now());
+        let min_age = Some(Duration::from_secs(3600));
+        assert_eq!(with_margin(&edges, min_age), U256::from(135));
+    }
+
+    #[test]
+    fn max_edge_share() {
+        let (a, b, c, d, ..) = addresses();
+        let edges = build_edges(vec![
+            edge(a, b, a, 10),
+            edge(b, d, b, 10),
+            edge(a, c, a, 10),
+            edge(c, d, c, 10),
+        ]);
+        let with_share = |max_edge_share| {
+            compute_flow_with_options(
+                &a,
+                &d,
+                &edges,
+                U256::from(10),
+                &FlowOptions {
+                    max_edge_share,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        assert_eq!(with_share(None).1.len(), 2);
+        // Half of the capacity of the intermediaries is used, the source's edges are exempt.
+        let (flow, transfers) = with_share(Some(50));
+        assert_eq!(flow, U256::from(10));
+        assert_eq!(transfers.len(), 4);
+        assert!(transfers.iter().all(|t| t.capacity == U256::from(5)));
+        assert_eq!(with_share(Some(20)).0, U256::from(4));
+    }
+
+    #[test]
+    fn overlay() {
+        let (a, b, c, t, x, _) = addresses();
+        let edges = build_edges(vec![edge(a, b, t, 10), edge(a, c, a, 3), edge(c, b, c, 3)]);
+        let with_overlay = |changes| {
+            compute_flow_with_options(
+                &a,
+                &b,
+                &edges,
+                U256::MAX,
+                &FlowOptions {
+                    overlay: Some(Arc::new(EdgeOverlay::new(changes))),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        assert_eq!(with_overlay(vec![]).0, U256::from(13));
+        // Remove the direct edge, add one with a different token and change a capacity.
+        let (flow, transfers) =
+            with_overlay(vec![edge(a, b, t, 0), edge(a, b, x, 4), edge(c, b, c, 1)]);
+        assert_eq!(flow, U256::from(5));
+        assert!(transfers.contains(&edge(a, b, x, 4)));
+        // The edge database is not modified.
+        assert_eq!(
+            compute_flow(&a, &b, &edges, U256::MAX, None, None)
+                .unwrap()
+                .0,
+            U256::from(13)
+        );
+    }
+
+    #[test]
+    fn overlay_with_token_preferences() {
+        let (a, b, c, t, x, _) = addresses();
+        let edges = build_edges(vec![edge(a, b, t, 10), edge(a, c, a, 3), edge(c, b, c, 3)]);
+        // The token x is only sent by a in the overlay.
+        let (flow, transfers) = compute_flow_with_options(
+            &a,
+            &b,
+            &edges,
+            U256::MAX,
+            &FlowOptions {
+                token_preferences: TokenPreferences {
+                    spend_tokens: Some(BTreeSet::from([x])),
+                    ..Default::default()
+                },
+                overlay: Some(Arc::new(EdgeOverlay::new(vec![edge(a, b, x, 4)]))),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(flow, U256::from(4));
+        assert_eq!(transfers, vec![edge(a, b, x, 4)]);
+    }
+
+    #[test]
+    fn max_transfers_searches_best_flow() {
+        let (a, b, c, d, t, _) = addresses();
+        let edges = build_edges(vec![
+            edge(a, b, a, 6),
+            edge(b, d, b, 6),
+            edge(a, d, c, 4),
+            edge(a, d, t, 4),
+            edge(a, d, d, 4),
+        ]);
+        let mut heuristic = IncrementalFlow::new(
+            &a,
+            &d,
+            &edges,
+            U256::MAX,
+            &FlowOptions {
+                max_transfers: Some(2),
+                ..Default::default()
+            },
+        );
+        heuristic.extend(None);
+        assert_eq!(heuristic.transfers().unwrap().0, U256::from(6));
+        let (flow, transfers) = compute_flow(&a, &d, &edges, U256::MAX, None, Some(2)).unwrap();
+        assert_eq!(flow, U256::from(8));
+        assert_eq!(transfers.len(), 2);
+    }
+
+    #[test]
+    fn exact() {
+        let (a, b, c, d, t, _) = addresses();
+        let edges = build_edges(vec![
+            edge(a, b, a, 7),
+            edge(b, d, b, 7),
+            edge(a, c, c, 5),
+            edge(c, d, c, 5),
+            edge(a, d, t, 3),
+        ]);
+        let exact = |requested_flow: u128, max_transfers| {
+            compute_flow_with_options(
+                &a,
+                &d,
+                &edges,
+                U256::from(requested_flow),
+                &FlowOptions {
+                    exact: true,
+                    max_transfers,
+                    ..Default::default()
+                },
+            )
+        };
+        for requested_flow in 1..=15 {
+            let (flow, _) = exact(requested_flow, None).unwrap();
+            assert_eq!(flow, U256::from(requested_flow));
+        }
+        assert_eq!(
+            exact(16, None),
+            Err(FlowError::InsufficientFlow {
+                requested: U256::from(16),
+                available: U256::from(15)
+            })
+        );
+        assert_eq!(
+            exact(15, Some(2)),
+            Err(FlowError::InsufficientFlow {
+                requested: U256::from(15),
+                available: U256::from(7)
+            })
+        );
+    }
+
+    #[test]
+    fn prune_flow_is_exact() {
+        let (s, x, y, t, _, _) = addresses();
+        // The paths s, x, t and s, y, t and s, x, y, t share edges, so pruning
+        // whole edges overshoots most amounts and paths have to be pruned partially.
+        let transfers = [
+            edge(s, x, s, 4),
+            edge(x, y, x, 2),
+            edge(x, t, x, 2),
+            edge(s, y, s, 2),
+            edge(y, t, y, 4),
+            edge(s, t, s, 2),
+        ];
+        let mut flow: HashMap<Node, HashMap<Node, U256>> = HashMap::new();
+        for e in &transfers {
+            let path = [
+                Node::Node(e.from),
+                Node::BalanceNode(e.from, e.token),
+                Node::TrustNode(e.to, e.token),
+                Node::Node(e.to),
+            ];
+            for pair in path.windows(2) {
+                *flow
+                    .entry(pair[0].clone())
+                    .or_default()
+                    .entry(pair[1].clone())
+                    .or_default() += e.capacity;
+            }
+        }
+        let total = U256::from(8);
+        for requested in 0..=8 {
+            let mut used_edges = flow.clone();
+            let remaining = prune_flow(&s, &t, total - U256::from(requested), &mut used_edges);
+            assert_eq!(remaining, U256::from(0));
+            let inflow = |n: &Node| {
+                used_edges
+                    .values()
+                    .filter_map(|out| out.get(n))
+                    .fold(U256::from(0), |sum, f| sum + *f)
+            };
+            let outflow = |n: &Node| {
+                used_edges.get(n).map_or(U256::from(0), |out| {
+                    out.values().fold(U256::from(0), |sum, f| sum + *f)
+                })
+            };
+            assert_eq!(inflow(&Node::Node(t)), U256::from(requested));
+            assert_eq!(outflow(&Node::Node(s)), U256::from(requested));
+            for n in flow.keys() {
+                if *n != Node::Node(s) {
+                    assert_eq!(inflow(n), outflow(n), "{requested}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn max_hops() {
+        let (a, b, c, d, t, _) = addresses();
+        let edges = build_edges(vec![
+            edge(a, b, a, 5),
+            edge(b, c, b, 5),
+            edge(c, d, c, 5),
+            edge(a, d, t, 3),
+        ]);
+        for (max_hops, expected_flow) in [(1, 3), (2, 3), (3, 8)] {
+            let (flow, transfers) = compute_flow_with_options(
+                &a,
+                &d,
+                &edges,
+                U256::MAX,
+                &FlowOptions {
+                    max_hops: Some(max_hops),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(flow, U256::from(expected_flow));
+            assert_eq!(verify_transfers(&edges, None, &a, &d, &transfers), Ok(flow));
+            let paths = decompose_transfers_with_max_hops(&a, &d, &transfers, max_hops);
+            let total = paths
+                .iter()
+                .fold(U256::from(0), |sum, path| sum + path.amount);
+            assert_eq!(total, flow);
+        }
+    }
+
+    #[test]
+    fn limit_path_lengths_reroutes_long_paths() {
+        let (s, x, v, sink, y, _) = addresses();
+        // The flow consists of the paths s, x, v, sink and s, v, y, sink, but
+        // decomposing it shortest paths first results in s, v, sink and s, x, v, y, sink.
+        let transfers = vec![
+            edge(s, x, s, 1),
+            edge(s, v, s, 1),
+            edge(x, v, x, 1),
+            edge(v, sink, v, 1),
+            edge(v, y, v, 1),
+            edge(y, sink, y, 1),
+        ];
+        assert_eq!(
+            limit_path_lengths(&s, &sink, transfers.clone(), 3).unwrap(),
+            (transfers.clone(), U256::from(0))
+        );
+        let (limited, lost) = limit_path_lengths(&s, &sink, transfers, 2).unwrap();
+        assert_eq!(lost, U256::from(1));
+        assert_eq!(limited, vec![edge(s, v, s, 1), edge(v, sink, v, 1)]);
+    }
 }
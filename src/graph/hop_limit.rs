@@ -0,0 +1,422 @@
+use crate::graph::Node;
+use crate::types::{Address, U256};
+use std::cmp::{min, Reverse};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+/// A flow from a source to a sink that is kept as a list of paths, each
+/// with at most a given number of hops (transfers).
+///
+/// The flow is found by repeatedly searching augmenting paths in the flow network
+/// whose states are pairs of a node and the number of hops used to reach it.
+/// Instead of residual edges, an augmenting path can take over the rest of an
+/// existing path at one of its nodes, while the start of the existing path continues
+/// on the rest of the augmenting path, possibly after giving back some of its
+/// last edges. Both resulting paths are checked against the hop limit,
+/// so the limit holds for all paths of the flow, including rerouted ones.
+/// If the limit is large enough to not matter, this is a regular augmenting
+/// path search with residual edges.
+#[derive(Clone, Debug, Default)]
+pub struct HopLimitedFlow {
+    paths: Vec<(Vec<Node>, U256)>,
+    /// The flow on each edge, i.e. the sum of the paths.
+    used_edges: HashMap<Node, HashMap<Node, U256>>,
+}
+
+#[derive(Clone, Copy)]
+enum Move {
+    /// Uses an edge of the flow network that has the given residual capacity.
+    Forward(U256),
+    /// Takes over the rest of `path` at position `at`. The start of `path`
+    /// continues at position `to`, giving back the edges in between.
+    Exchange { path: usize, at: usize, to: usize },
+}
+
+type State = (Node, u64);
+
+impl HopLimitedFlow {
+    /// The paths of the flow (as nodes of the flow network) together with their amounts.
+    pub fn paths(&self) -> &[(Vec<Node>, U256)] {
+        &self.paths
+    }
+
+    /// The flow on each edge of the flow network.
+    pub fn used_edges(&self) -> &HashMap<Node, HashMap<Node, U256>> {
+        &self.used_edges
+    }
+
+    /// Adds flow from `source` to `sink` along paths of at most `max_hops` hops until
+    /// no further flow is found or `limit` is reached. The capacities of the flow network
+    /// are given by `capacities`, each hop ends in a `Node::Node`.
+    ///
+    /// Returns the additional flow.
+    pub fn augment(
+        &mut self,
+        source: &Node,
+        sink: &Node,
+        max_hops: u64,
+        limit: U256,
+        mut capacities: impl FnMut(&Node) -> HashMap<Node, U256>,
+    ) -> U256 {
+        let mut added = U256::from(0);
+        while added < limit {
+            let Some(moves) = self.search(source, sink, max_hops, &mut capacities) else {
+                break;
+            };
+            let amount = self.apply(source, &moves, limit - added);
+            if amount == U256::from(0) {
+                break;
+            }
+            added += amount;
+        }
+        added
+    }
+
+    /// Removes `flow_to_prune` from the flow. Paths that start with a token that
+    /// is not in `preferred_tokens` (if set) are removed first, then long paths before short paths.
+    ///
+    /// Returns the flow that could not be removed because the flow is too small.
+    pub fn prune(
+        &mut self,
+        mut flow_to_prune: U256,
+        preferred_tokens: Option<&BTreeSet<Address>>,
+    ) -> U256 {
+        let is_preferred = |path: &[Node]| match (preferred_tokens, path.get(1)) {
+            (Some(tokens), Some(Node::BalanceNode(_, token))) => tokens.contains(token),
+            _ => true,
+        };
+        let mut order = (0..self.paths.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| {
+            let path = &self.paths[*i].0;
+            (is_preferred(path), Reverse(path.len()), Reverse(*i))
+        });
+        for i in order {
+            if flow_to_prune == U256::from(0) {
+                break;
+            }
+            let reduction = min(flow_to_prune, self.paths[i].1);
+            self.paths[i].1 -= reduction;
+            flow_to_prune -= reduction;
+        }
+        self.paths.retain(|(_, amount)| *amount != U256::from(0));
+        self.update_used_edges();
+        flow_to_prune
+    }
+
+    /// Breadth-first search for an augmenting path from `(source, 0)` to
+    /// the sink with at most `max_hops` hops. Returns the moves along the path.
+    fn search(
+        &self,
+        source: &Node,
+        sink: &Node,
+        max_hops: u64,
+        capacities: &mut impl FnMut(&Node) -> HashMap<Node, U256>,
+    ) -> Option<Vec<(State, Move)>> {
+        let hops = self
+            .paths
+            .iter()
+            .map(|(path, _)| hop_counts(path))
+            .collect::<Vec<_>>();
+        // For each node, the paths through it and the position in the path.
+        let mut through = HashMap::<&Node, Vec<(usize, usize)>>::new();
+        for (i, (path, _)) in self.paths.iter().enumerate() {
+            for (position, node) in path.iter().enumerate().skip(1) {
+                through.entry(node).or_default().push((i, position));
+            }
+        }
+
+        let start = (source.clone(), 0);
+        let mut parents = HashMap::<State, (State, Move)>::new();
+        let mut queue = VecDeque::from([start.clone()]);
+        while let Some(current) = queue.pop_front() {
+            let (node, hops_here) = &current;
+            if node == sink {
+                let mut moves = vec![];
+                let mut state = current;
+                while let Some((parent, m)) = parents.get(&state) {
+                    moves.push((state, *m));
+                    state = parent.clone();
+                }
+                moves.reverse();
+                return Some(moves);
+            }
+            let mut next_states = vec![];
+            if !matches!(node, Node::Node(_)) || *hops_here < max_hops {
+                let mut adjacent = capacities(node).into_iter().collect::<Vec<_>>();
+                adjacent.sort();
+                for (next, capacity) in adjacent {
+                    let used = self.flow_on(node, &next);
+                    if next == *source || capacity <= used {
+                        continue;
+                    }
+                    let next_hops = hops_here + u64::from(matches!(next, Node::Node(_)));
+                    next_states.push(((next, next_hops), Move::Forward(capacity - used)));
+                }
+            }
+            let used_paths = used_paths(&parents, &current);
+            for (path, at) in through.get(node).into_iter().flatten() {
+                let remaining_hops = hops[*path].last().unwrap() - hops[*path][*at];
+                if used_paths.contains(path) || hops_here + remaining_hops > max_hops {
+                    continue;
+                }
+                for to in (1..=*at).rev() {
+                    let state = (self.paths[*path].0[to].clone(), hops[*path][to]);
+                    let exchange = Move::Exchange {
+                        path: *path,
+                        at: *at,
+                        to,
+                    };
+                    next_states.push((state, exchange));
+                }
+            }
+            for (state, m) in next_states {
+                if state != start && !parents.contains_key(&state) {
+                    parents.insert(state.clone(), (current.clone(), m));
+                    queue.push_back(state);
+                }
+            }
+        }
+        None
+    }
+
+    /// Pushes up to `limit` along the augmenting path given by `moves`
+    /// and returns the amount pushed.
+    fn apply(&mut self, source: &Node, moves: &[(State, Move)], limit: U256) -> U256 {
+        // How often each edge is used by the augmenting path, minus the number
+        // of times it is given back, and the residual capacity of the used edges.
+        let mut usage = HashMap::<(Node, Node), (i64, U256)>::new();
+        let mut amount = limit;
+        let mut previous = source.clone();
+        for ((node, _), m) in moves {
+            match m {
+                Move::Forward(residual) => {
+                    let entry = usage.entry((previous.clone(), node.clone())).or_default();
+                    *entry = (entry.0 + 1, *residual);
+                }
+                Move::Exchange { path, at, to } => {
+                    let (nodes, path_amount) = &self.paths[*path];
+                    amount = min(amount, *path_amount);
+                    for pair in nodes[*to..=*at].windows(2) {
+                        usage
+                            .entry((pair[0].clone(), pair[1].clone()))
+                            .or_default()
+                            .0 -= 1;
+                    }
+                }
+            }
+            previous = node.clone();
+        }
+        for (count, residual) in usage.values() {
+            if *count > 0 {
+                amount = min(amount, *residual / U256::from(*count as u128));
+            }
+        }
+        if amount == U256::from(0) {
+            return amount;
+        }
+
+        let mut new_paths = vec![];
+        let mut current = vec![source.clone()];
+        for ((node, _), m) in moves {
+            match m {
+                Move::Forward(_) => current.push(node.clone()),
+                Move::Exchange { path, at, to } => {
+                    let nodes = &self.paths[*path].0;
+                    current.extend(nodes[*at + 1..].iter().cloned());
+                    new_paths.push(current);
+                    current = nodes[..=*to].to_vec();
+                }
+            }
+        }
+        new_paths.push(current);
+        for (_, m) in moves {
+            if let Move::Exchange { path, .. } = m {
+                self.paths[*path].1 -= amount;
+            }
+        }
+        self.paths.retain(|(_, amount)| *amount != U256::from(0));
+        self.paths.extend(
+            new_paths
+                .into_iter()
+                .map(|path| (without_cycles(path), amount)),
+        );
+        self.update_used_edges();
+        amount
+    }
+
+    fn flow_on(&self, from: &Node, to: &Node) -> U256 {
+        self.used_edges
+            .get(from)
+            .and_then(|out| out.get(to))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn update_used_edges(&mut self) {
+        self.used_edges.clear();
+        for (path, amount) in &self.paths {
+            for pair in path.windows(2) {
+                *self
+                    .used_edges
+                    .entry(pair[0].clone())
+                    .or_default()
+                    .entry(pair[1].clone())
+                    .or_default() += *amount;
+            }
+        }
+    }
+}
+
+/// Returns the number of hops used to reach each node of `path`.
+fn hop_counts(path: &[Node]) -> Vec<u64> {
+    let mut hops = 0;
+    path.iter()
+        .enumerate()
+        .map(|(i, node)| {
+            if i > 0 && matches!(node, Node::Node(_)) {
+                hops += 1;
+            }
+            hops
+        })
+        .collect()
+}
+
+/// Returns the paths the augmenting path to `state` has taken over so far.
+fn used_paths(parents: &HashMap<State, (State, Move)>, state: &State) -> HashSet<usize> {
+    let mut used = HashSet::new();
+    let mut state = state;
+    while let Some((parent, m)) = parents.get(state) {
+        if let Move::Exchange { path, .. } = m {
+            used.insert(*path);
+        }
+        state = parent;
+    }
+    used
+}
+
+/// Removes the cycles from a path.
+fn without_cycles(path: Vec<Node>) -> Vec<Node> {
+    let mut result: Vec<Node> = Vec::with_capacity(path.len());
+    for node in path {
+        if let Some(position) = result.iter().position(|n| *n == node) {
+            result.truncate(position);
+        }
+        result.push(node);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::addresses;
+
+    /// Computes the hop limited flow in a network where every edge is one hop.
+    fn hop_limited_flow(
+        edges: &[(Address, Address, u128)],
+        source: &Address,
+        sink: &Address,
+        max_hops: u64,
+    ) -> HopLimitedFlow {
+        let mut capacities: HashMap<Node, HashMap<Node, U256>> = HashMap::new();
+        for (from, to, capacity) in edges {
+            capacities
+                .entry(Node::Node(*from))
+                .or_default()
+                .insert(Node::Node(*to), U256::from(*capacity));
+        }
+        let mut flow = HopLimitedFlow::default();
+        flow.augment(
+            &Node::Node(*source),
+            &Node::Node(*sink),
+            max_hops,
+            U256::MAX,
+            |node| capacities.get(node).cloned().unwrap_or_default(),
+        );
+        flow
+    }
+
+    fn paths(flow: &HopLimitedFlow) -> Vec<(Vec<Address>, U256)> {
+        let mut paths = flow
+            .paths()
+            .iter()
+            .map(|(nodes, amount)| {
+                let addresses = nodes
+                    .iter()
+                    .map(|node| match node {
+                        Node::Node(address) => *address,
+                        _ => panic!(),
+                    })
+                    .collect();
+                (addresses, *amount)
+            })
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn reroutes_existing_paths() {
+        let (s, a, b, c, d, t) = addresses();
+        // The first path is s, a, c, t. The second path s, b, c, t
+        // takes over its last edge, so that it has to continue via d.
+        let edges = [
+            (s, a, 1),
+            (s, b, 1),
+            (a, c, 1),
+            (b, c, 1),
+            (c, t, 1),
+            (a, d, 1),
+            (d, t, 1),
+        ];
+        let flow = hop_limited_flow(&edges, &s, &t, 3);
+        assert_eq!(
+            paths(&flow),
+            vec![
+                (vec![s, a, d, t], U256::from(1)),
+                (vec![s, b, c, t], U256::from(1))
+            ]
+        );
+        assert_eq!(
+            flow.used_edges()[&Node::Node(c)][&Node::Node(t)],
+            U256::from(1)
+        );
+        assert!(!flow.used_edges()[&Node::Node(a)].contains_key(&Node::Node(c)));
+    }
+
+    #[test]
+    fn does_not_reroute_beyond_hop_limit() {
+        let (s, x, v, t, y, _) = addresses();
+        // The first path is s, v, t. Rerouting it into s, x, v, t and s, v, y, t
+        // needs paths with three hops.
+        let edges = [
+            (s, x, 1),
+            (s, v, 1),
+            (x, v, 1),
+            (v, t, 1),
+            (v, y, 1),
+            (y, t, 1),
+        ];
+        assert_eq!(
+            paths(&hop_limited_flow(&edges, &s, &t, 3)),
+            vec![
+                (vec![s, x, v, t], U256::from(1)),
+                (vec![s, v, y, t], U256::from(1))
+            ]
+        );
+        assert_eq!(
+            paths(&hop_limited_flow(&edges, &s, &t, 2)),
+            vec![(vec![s, v, t], U256::from(1))]
+        );
+    }
+
+    #[test]
+    fn prune_removes_long_paths_first() {
+        let (s, a, b, c, _, t) = addresses();
+        let edges = [(s, a, 5), (a, t, 5), (s, b, 3), (b, c, 3), (c, t, 3)];
+        let mut flow = hop_limited_flow(&edges, &s, &t, 3);
+        assert_eq!(flow.prune(U256::from(4), None), U256::from(0));
+        assert_eq!(paths(&flow), vec![(vec![s, a, t], U256::from(4))]);
+        assert_eq!(flow.prune(U256::from(5), None), U256::from(1));
+        assert!(flow.paths().is_empty());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::graph::adjacencies::{incoming, outgoing, Adjacencies};
+use crate::graph::flow::dinic_max_flow;
+use crate::graph::Node;
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::{Address, U256};
+use std::cmp::{min, Reverse};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+use std::sync::Arc;
+
+/// An address together with the maximum amount it can send to (or receive from)
+/// the address the liquidity query was made for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Liquidity {
+    pub address: Address,
+    pub amount: U256,
+    /// True if `amount` is the max flow. Otherwise, it is an upper bound
+    /// (only returned by `reachable` if it does not compute the max flows).
+    pub exact: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    /// Flows from the other addresses to the root.
+    Inbound,
+    /// Flows from the root to the other addresses.
+    Outbound,
+}
+
+/// Returns all addresses within `max_hops` transfers of `sink` together with the
+/// max flow each of them can send to `sink`, largest amounts first.
+/// If there is an overlay, its changes are applied to `edges`.
+///
+/// A single search backwards from the sink on the incoming edges finds the amount each
+/// address can send along its best path of at most `max_hops` transfers. If that reaches
+/// the upper bound (what the address can send on the first hop, capped by what the sink
+/// can receive), it is the max flow. Otherwise, the max flow is computed lazily as in
+/// `reachable`, so taking only the first results skips the computation for all others.
+pub fn max_receivable(
+    sink: &Address,
+    edges: &EdgeDB,
+    overlay: Option<Arc<EdgeOverlay>>,
+    max_hops: u64,
+) -> Liquidities {
+    Liquidities::new(sink, edges, overlay, max_hops, Direction::Inbound, true)
+}
+
+/// Returns all addresses `source` can reach in at most `max_hops` transfers together
+/// with an upper bound of the amount it can send to each of them (what the source can
+/// send on the first hop, capped by what the address can receive), largest amounts first.
+///
+/// If `exact` is true, the max flow to each address is computed instead, lazily: Each address
+/// starts with its upper bound and its max flow is only computed once that bound is the
+/// largest one left, so taking only the first results skips the computation for all others.
+pub fn reachable(
+    source: &Address,
+    edges: &EdgeDB,
+    overlay: Option<Arc<EdgeOverlay>>,
+    max_hops: u64,
+    exact: bool,
+) -> Liquidities {
+    Liquidities::new(source, edges, overlay, max_hops, Direction::Outbound, exact)
+}
+
+/// Iterator over the results of `max_receivable` and `reachable`.
+pub struct Liquidities {
+    root: Address,
+    direction: Direction,
+    /// The edges between the addresses within `max_hops` of the root.
+    edges: EdgeDB,
+    max_hops: u64,
+    /// If false, the upper bounds are returned without computing the max flows.
+    exact: bool,
+    /// The amounts of the remaining addresses, and whether they are the max flows.
+    queue: BinaryHeap<(U256, bool, Reverse<Address>)>,
+}
+
+impl Liquidities {
+    fn new(
+        root: &Address,
+        edges: &EdgeDB,
+        overlay: Option<Arc<EdgeOverlay>>,
+        max_hops: u64,
+        direction: Direction,
+        exact: bool,
+    ) -> Liquidities {
+        let overlay = overlay.as_deref();
+        let distances = distances_from(root, edges, overlay, max_hops, direction);
+        let local_edges = EdgeDB::new(
+            distances
+                .keys()
+                .flat_map(|to| incoming(edges, overlay, to))
+                .filter(|edge| distances.contains_key(&edge.from))
+                .collect(),
+        );
+        let queue = match direction {
+            Direction::Inbound => widest_paths_to(root, &local_edges, max_hops)
+                .into_iter()
+                .filter(|(_, amount)| *amount > U256::from(0))
+                .map(|(address, amount)| {
+                    let bound = min(
+                        sendable(&local_edges, &address),
+                        receivable(&local_edges, root),
+                    );
+                    (bound, amount == bound, Reverse(address))
+                })
+                .collect(),
+            Direction::Outbound => distances
+                .keys()
+                .filter(|address| *address != root)
+                .map(|address| {
+                    let bound = min(
+                        sendable(&local_edges, root),
+                        receivable(&local_edges, address),
+                    );
+                    (bound, false, Reverse(*address))
+                })
+                .filter(|(bound, ..)| *bound > U256::from(0))
+                .collect(),
+        };
+        Liquidities {
+            root: *root,
+            direction,
+            edges: local_edges,
+            max_hops,
+            exact,
+            queue,
+        }
+    }
+}
+
+impl Iterator for Liquidities {
+    type Item = Liquidity;
+
+    fn next(&mut self) -> Option<Liquidity> {
+        while let Some((amount, exact, Reverse(address))) = self.queue.pop() {
+            if exact || !self.exact {
+                return Some(Liquidity {
+                    address,
+                    amount,
+                    exact,
+                });
+            }
+            let (source, sink) = match self.direction {
+                Direction::Inbound => (address, self.root),
+                Direction::Outbound => (self.root, address),
+            };
+            let flow = dinic_max_flow(
+                &mut Adjacencies::new(&self.edges),
+                &Node::Node(source),
+                &Node::Node(sink),
+                Some(self.max_hops * 3),
+                &mut HashMap::new(),
+            );
+            if flow > U256::from(0) {
+                self.queue.push((flow, true, Reverse(address)));
+            }
+        }
+        None
+    }
+}
+
+/// Returns the addresses that can reach `root` (inbound) or can be reached from `root`
+/// (outbound) in at most `max_hops` transfers, together with their distance in transfers.
+fn distances_from(
+    root: &Address,
+    edges: &EdgeDB,
+    overlay: Option<&EdgeOverlay>,
+    max_hops: u64,
+    direction: Direction,
+) -> HashMap<Address, u64> {
+    let mut distances = HashMap::from([(*root, 0)]);
+    let mut queue = VecDeque::from([*root]);
+    while let Some(address) = queue.pop_front() {
+        let distance = distances[&address];
+        if distance >= max_hops {
+            continue;
+        }
+        let neighbors: Vec<Address> = match direction {
+            Direction::Inbound => incoming(edges, overlay, &address).map(|e| e.from).collect(),
+            Direction::Outbound => outgoing(edges, overlay, &address).map(|e| e.to).collect(),
+        };
+        for neighbor in neighbors {
+            if let Entry::Vacant(entry) = distances.entry(neighbor) {
+                entry.insert(distance + 1);
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    distances
+}
+
+/// Returns the addresses that can send to `sink` in at most `max_hops` transfers, each with
+/// the largest amount it can send along a single path (the bottleneck of the path).
+///
+/// Since every edge capacity is at most the balance of the sender and the trust limit of
+/// the receiver, the bottleneck of a path in the flow network is its smallest edge capacity.
+/// The amounts are extended backwards from the sink by one transfer per round, so after
+/// `max_hops` rounds, each amount is the best over all paths with at most that many transfers.
+fn widest_paths_to(sink: &Address, edges: &EdgeDB, max_hops: u64) -> HashMap<Address, U256> {
+    let mut widest = HashMap::from([(*sink, U256::MAX)]);
+    let mut changed = vec![*sink];
+    for _ in 0..max_hops {
+        let previous = widest.clone();
+        let mut next_changed = vec![];
+        for address in changed {
+            for edge in edges.incoming(&address) {
+                let amount = min(edge.capacity, previous[&address]);
+                let best = widest.entry(edge.from).or_default();
+                if amount > *best {
+                    *best = amount;
+                    next_changed.push(edge.from);
+                }
+            }
+        }
+        next_changed.sort();
+        next_changed.dedup();
+        changed = next_changed;
+    }
+    widest.remove(sink);
+    widest
+}
+
+/// Returns an upper bound of how much `address` can receive on the last hop.
+fn receivable(edges: &EdgeDB, address: &Address) -> U256 {
+    edges
+        .incoming(address)
+        .iter()
+        .fold(U256::from(0), |sum, edge| sum + edge.capacity)
+}
+
+/// Returns how much `address` can send on the first hop, i.e. the sum
+/// over all tokens of the largest capacity of its edges in that token.
+fn sendable(edges: &EdgeDB, address: &Address) -> U256 {
+    let mut by_token = BTreeMap::<Address, U256>::new();
+    for edge in edges.outgoing(address) {
+        let capacity = by_token.entry(edge.token).or_default();
+        if edge.capacity > *capacity {
+            *capacity = edge.capacity;
+        }
+    }
+    by_token
+        .values()
+        .fold(U256::from(0), |sum, capacity| sum + *capacity)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::compute_flow;
+    use crate::test_utils::{addresses, edge};
+
+    #[test]
+    fn max_receivable_sorted() {
+        let (a, b, c, d, e, ..) = addresses();
+        // a can send a lot to b, but b can only pass on 6 to d, 4 of them on the best path,
+        // so the max flows of a and b are larger than their best paths.
+        let edges = EdgeDB::new(vec![
+            edge(a, b, a, 20),
+            edge(b, d, b, 4),
+            edge(c, d, c, 7),
+            edge(b, d, e, 2),
+            edge(e, a, e, 9),
+        ]);
+        let result = max_receivable(&d, &edges, None, 2).collect::<Vec<_>>();
+        let amounts = result
+            .iter()
+            .map(|l| (l.address, l.amount, l.exact))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            amounts,
+            vec![
+                (c, U256::from(7), true),
+                (a, U256::from(6), true),
+                (b, U256::from(6), true)
+            ]
+        );
+        for l in &result {
+            let (flow, _) = compute_flow(&l.address, &d, &edges, U256::MAX, None, None).unwrap();
+            assert_eq!(l.amount, flow);
+        }
+        // e is three hops away.
+        assert_eq!(max_receivable(&d, &edges, None, 3).count(), 4);
+
+        // Without the edge c -> d, c cannot send anything.
+        let overlay = Arc::new(EdgeOverlay::new(vec![edge(c, d, c, 0)]));
+        let result = max_receivable(&d, &edges, Some(overlay), 2).collect::<Vec<_>>();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].amount, U256::from(6));
+    }
+
+    #[test]
+    fn max_receivable_respects_max_hops() {
+        let (a, b, c, d, ..) = addresses();
+        // The direct edge is narrower than the path via b and c.
+        let edges = EdgeDB::new(vec![
+            edge(a, d, a, 2),
+            edge(a, b, a, 9),
+            edge(b, c, b, 9),
+            edge(c, d, c, 9),
+        ]);
+        let amount_of_a = |max_hops| {
+            max_receivable(&d, &edges, None, max_hops)
+                .find(|l| l.address == a)
+                .map(|l| l.amount)
+        };
+        assert_eq!(amount_of_a(1), Some(U256::from(2)));
+        assert_eq!(amount_of_a(2), Some(U256::from(2)));
+        assert_eq!(amount_of_a(3), Some(U256::from(9)));
+    }
+
+    #[test]
+    fn reachable_bounds_and_exact() {
+        let (a, b, c, d, ..) = addresses();
+        // The bottleneck b -> c is not visible in the bound for d.
+        let edges = EdgeDB::new(vec![edge(a, b, a, 10), edge(b, c, b, 2), edge(c, d, c, 10)]);
+        let amounts = |exact| {
+            reachable(&a, &edges, None, 3, exact)
+                .map(|l| (l.address, l.amount, l.exact))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            amounts(false),
+            vec![
+                (b, U256::from(10), false),
+                (d, U256::from(10), false),
+                (c, U256::from(2), false)
+            ]
+        );
+        assert_eq!(
+            amounts(true),
+            vec![
+                (b, U256::from(10), true),
+                (d, U256::from(2), true),
+                (c, U256::from(2), true)
+            ]
+        );
+        assert_eq!(reachable(&a, &edges, None, 2, true).count(), 2);
+    }
+}
@@ -2,7 +2,22 @@ use crate::types::Address;
 use std::fmt::{Display, Formatter};
 
 mod adjacencies;
+mod alternatives;
+mod batches;
+mod cut_tree;
+mod dependencies;
+mod diagnostics;
+mod error;
 mod flow;
+mod hop_limit;
+mod liquidity;
+mod multi;
+mod optimize;
+mod options;
+mod paths;
+mod plan;
+mod transfer_limit;
+mod verify;
 
 // An edge from the capacity network is
 // from, token, to -> capacity
@@ -17,12 +32,17 @@ mod flow;
 // B: the actual capacity of the capacity-network edge (from, token, to), or the "send limit" from "from" to "to" in "token" tokens
 // C: if "token" is C's token (this is a "send to owner" edge): infinity or the sum of all incoming edges.
 //    otherwise: the max of all capacity-network edges of the form (*, token, to) or the trust limit of "to" for "token" tokens.
+//
+// For flows with multiple sources or sinks, there is an additional SuperSource node with
+// edges to all sources and a SuperSink node with edges from all sinks.
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone, PartialOrd, Ord)]
 pub enum Node {
     Node(Address),
     BalanceNode(Address, Address),
     TrustNode(Address, Address),
+    SuperSource,
+    SuperSink,
 }
 
 pub fn node_as_address(node: &Node) -> &Address {
@@ -47,9 +67,33 @@ impl Display for Node {
             Node::Node(address) => write!(f, "{address}"),
             Node::BalanceNode(from, token) => write!(f, "(bal {from} x {token})"),
             Node::TrustNode(to, token) => write!(f, "(trust {to} x {token})"),
+            Node::SuperSource => write!(f, "(super source)"),
+            Node::SuperSink => write!(f, "(super sink)"),
         }
     }
 }
 
+pub use crate::graph::alternatives::{compute_alternatives, Alternative};
+pub use crate::graph::batches::{split_into_batches, BatchLimits};
+pub use crate::graph::cut_tree::CutTree;
+pub use crate::graph::dependencies::transfer_dependencies;
+pub use crate::graph::diagnostics::{diagnose_zero_flow, FlowDiagnostics, ZeroFlowReason};
+pub use crate::graph::error::FlowError;
 pub use crate::graph::flow::compute_flow;
+pub use crate::graph::flow::compute_flow_with_options;
 pub use crate::graph::flow::transfers_to_dot;
+pub use crate::graph::flow::IncrementalFlow;
+pub use crate::graph::liquidity::{max_receivable, reachable, Liquidities, Liquidity};
+pub use crate::graph::multi::{compute_multi_flow, AttributedTransfers};
+pub use crate::graph::optimize::{optimize_transfers, OptimizationReport};
+pub use crate::graph::options::{
+    CapacityMargin, FlowOptions, Objective, SpendOrder, TokenPreferences,
+};
+pub use crate::graph::paths::{
+    decompose_transfers, decompose_transfers_with_max_hops, TransferPath,
+};
+pub use crate::graph::plan::{plan_transfers, AllocationOrder, Payment, PlannedPayment};
+pub use crate::graph::transfer_limit::{
+    compute_flow_with_transfer_limit, TransferLimitedFlow, DEFAULT_TIME_BUDGET,
+};
+pub use crate::graph::verify::{verify_transfers, TransferViolation};
@@ -0,0 +1,205 @@
+use crate::graph::adjacencies::Adjacencies;
+use crate::graph::error::FlowError;
+use crate::graph::flow::{dinic_max_flow, transfers_from_flow};
+use crate::graph::paths::decompose_into_paths;
+use crate::graph::{node_as_address, Node};
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::{Address, Edge, U256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+type FlowDistribution = HashMap<Node, HashMap<Node, U256>>;
+
+/// The part of a multi-source / multi-sink flow that goes from
+/// one of the sources to one of the sinks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttributedTransfers {
+    pub source: Address,
+    pub sink: Address,
+    pub flow: U256,
+    pub transfers: Vec<Edge>,
+}
+
+/// Computes a flow from several sources to several sinks.
+///
+/// All sources are connected to a super source and all sinks to a super sink,
+/// and the max flow between those two is computed. The flow is then decomposed
+/// into paths so that each transfer can be attributed to the source it originates
+/// from and the sink it is destined for.
+///
+/// # Arguments
+///
+/// * `sources` - The source addresses, each with an optional limit on how much it sends.
+/// * `sinks` - The sink addresses, each with the amount it should receive at most.
+/// * `edges` - The EdgeDB containing the edges of the flow network.
+/// * `overlay` - Optional changes applied to `edges`.
+/// * `max_distance` - An optional maximum distance constraint (not counting the super source and sink).
+///
+/// # Returns
+///
+/// A tuple containing:
+/// * The total flow value.
+/// * The sorted transfers for each (source, sink) pair with non-zero flow.
+///
+/// Or an error if an address is both a source and a sink, or if the transfers cannot be ordered.
+pub fn compute_multi_flow(
+    sources: &[(Address, Option<U256>)],
+    sinks: &[(Address, U256)],
+    edges: &EdgeDB,
+    overlay: Option<Arc<EdgeOverlay>>,
+    max_distance: Option<u64>,
+) -> Result<(U256, Vec<AttributedTransfers>), FlowError> {
+    if let Some((address, _)) = sources
+        .iter()
+        .find(|(source, _)| sinks.iter().any(|(sink, _)| sink == source))
+    {
+        return Err(FlowError::SourceIsSink(*address));
+    }
+    let mut adjacencies = Adjacencies::new(edges);
+    if let Some(overlay) = overlay {
+        adjacencies.set_overlay(overlay);
+    }
+    adjacencies.add_super_source_and_sink(
+        sources
+            .iter()
+            .map(|(address, limit)| (*address, limit.unwrap_or(U256::MAX)))
+            .collect(),
+        sinks.iter().cloned().collect(),
+    );
+    let mut flow_distribution = HashMap::new();
+    let flow = dinic_max_flow(
+        &mut adjacencies,
+        &Node::SuperSource,
+        &Node::SuperSink,
+        max_distance.map(|d| d + 2),
+        &mut flow_distribution,
+    );
+
+    // Split the flow by (source, sink) pair.
+    let mut flows_by_pair = BTreeMap::<(Address, Address), (U256, FlowDistribution)>::new();
+    for (path, amount) in
+        decompose_into_paths(&Node::SuperSource, &Node::SuperSink, &flow_distribution)
+    {
+        let inner = &path[1..path.len() - 1];
+        let source = *node_as_address(&inner[0]);
+        let sink = *node_as_address(&inner[inner.len() - 1]);
+        let (pair_flow, used_edges) = flows_by_pair.entry((source, sink)).or_default();
+        *pair_flow += amount;
+        for w in inner.windows(2) {
+            *used_edges
+                .entry(w[0].clone())
+                .or_default()
+                .entry(w[1].clone())
+                .or_default() += amount;
+        }
+    }
+
+    // The pairs share the edges, so intermediaries are only bypassed
+    // on edges with capacity left over by all pairs together.
+    let unused_capacity = |from: &Address, to: &Address, token: &Address| {
+        let used = flow_distribution
+            .get(&Node::BalanceNode(*from, *token))
+            .and_then(|out| out.get(&Node::TrustNode(*to, *token)))
+            .copied()
+            .unwrap_or_default();
+        adjacencies
+            .usable_capacity(from, to, token)
+            .filter(|capacity| *capacity >= used)
+            .map(|capacity| capacity - used)
+    };
+    let attributed = flows_by_pair
+        .into_iter()
+        .map(|((source, sink), (flow, used_edges))| {
+            let (transfers, _) = transfers_from_flow(
+                &source,
+                &sink,
+                &flow,
+                used_edges,
+                U256::from(0),
+                unused_capacity,
+            )?;
+            Ok(AttributedTransfers {
+                source,
+                sink,
+                flow,
+                transfers,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((flow, attributed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{addresses, edge};
+
+    #[test]
+    fn attributed_to_sources_and_sinks() {
+        let (a, b, c, d, t, _) = addresses();
+        let edges = EdgeDB::new(vec![edge(a, c, a, 5), edge(a, d, t, 3), edge(b, d, b, 4)]);
+        let (flow, attributed) = compute_multi_flow(
+            &[(a, None), (b, None)],
+            &[(c, U256::from(10)), (d, U256::from(10))],
+            &edges,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(flow, U256::from(12));
+        assert_eq!(
+            attributed,
+            vec![
+                AttributedTransfers {
+                    source: a,
+                    sink: c,
+                    flow: U256::from(5),
+                    transfers: vec![edge(a, c, a, 5)],
+                },
+                AttributedTransfers {
+                    source: a,
+                    sink: d,
+                    flow: U256::from(3),
+                    transfers: vec![edge(a, d, t, 3)],
+                },
+                AttributedTransfers {
+                    source: b,
+                    sink: d,
+                    flow: U256::from(4),
+                    transfers: vec![edge(b, d, b, 4)],
+                },
+            ]
+        );
+
+        let (flow, attributed) = compute_multi_flow(
+            &[(a, Some(U256::from(6))), (b, None)],
+            &[(c, U256::from(10)), (d, U256::from(5))],
+            &edges,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(flow, U256::from(10));
+        let sent_by_a = attributed
+            .iter()
+            .filter(|t| t.source == a)
+            .fold(U256::from(0), |sum, t| sum + t.flow);
+        let received_by_d = attributed
+            .iter()
+            .filter(|t| t.sink == d)
+            .fold(U256::from(0), |sum, t| sum + t.flow);
+        assert_eq!(sent_by_a, U256::from(6));
+        assert_eq!(received_by_d, U256::from(5));
+
+        assert_eq!(
+            compute_multi_flow(
+                &[(a, None), (b, None)],
+                &[(b, U256::from(10)), (d, U256::from(10))],
+                &edges,
+                None,
+                None,
+            ),
+            Err(FlowError::SourceIsSink(b))
+        );
+    }
+}
@@ -0,0 +1,371 @@
+use crate::types::{Address, Edge, U256};
+use std::cmp::{max, min, Reverse};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Addresses with the amounts they send or receive.
+type Amounts = Vec<(Address, U256)>;
+
+/// Statistics about the changes made by `optimize_transfers`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OptimizationReport {
+    pub steps_before: usize,
+    pub steps_after: usize,
+    /// Number of transfers merged into a parallel transfer with the same (from, to, token).
+    pub merged_parallel: usize,
+    /// Number of transfers netted out against a transfer in the opposite direction.
+    pub netted_opposite: usize,
+    /// Number of intermediaries bypassed (fully or partially) in chains of transfers.
+    pub collapsed_chains: usize,
+}
+
+impl OptimizationReport {
+    pub fn steps_saved(&self) -> usize {
+        self.steps_before - self.steps_after
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            stepsBefore: self.steps_before,
+            stepsAfter: self.steps_after,
+            stepsSaved: self.steps_saved(),
+            mergedParallel: self.merged_parallel,
+            nettedOpposite: self.netted_opposite,
+            collapsedChains: self.collapsed_chains,
+        }
+    }
+}
+
+/// Reduces the number of transfers without changing the net amount each address
+/// sends or receives in each token:
+///
+/// * Parallel transfers with the same (from, to, token) are merged.
+/// * Transfers (A, B, T) and (B, A, T) are netted out against each other.
+/// * Chains (A, B, T), (B, C, T) are collapsed into (A, C, T). If the amounts do not match,
+///   they are split, which is done if it reduces the number of transfers via B.
+///   Chains are only collapsed if there is an edge (A, C, T) whose `capacity` fits the
+///   amount of the new transfer (together with what A already sends to C in T).
+///
+/// Merges that would result in transfers below `min_amount` are skipped.
+/// The result is not sorted.
+pub fn optimize_transfers(
+    transfers: Vec<Edge>,
+    min_amount: U256,
+    capacity: impl Fn(&Address, &Address, &Address) -> Option<U256>,
+) -> (Vec<Edge>, OptimizationReport) {
+    let mut optimizer = Optimizer {
+        min_amount,
+        report: OptimizationReport {
+            steps_before: transfers.len(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    for transfer in transfers {
+        optimizer.add(
+            &transfer.from,
+            &transfer.to,
+            &transfer.token,
+            transfer.capacity,
+        );
+    }
+
+    let mut queue = optimizer
+        .incoming
+        .keys()
+        .cloned()
+        .collect::<BTreeSet<(Address, Address)>>();
+    while let Some((via, token)) = queue.pop_first() {
+        for (from, to) in optimizer.collapse(&via, &token, &capacity) {
+            queue.insert((from, token));
+            queue.insert((to, token));
+        }
+    }
+
+    let transfers = optimizer
+        .amounts
+        .into_iter()
+        .map(|((from, to, token), capacity)| Edge {
+            from,
+            to,
+            token,
+            capacity,
+        })
+        .collect::<Vec<_>>();
+    optimizer.report.steps_after = transfers.len();
+    (transfers, optimizer.report)
+}
+
+#[derive(Default)]
+struct Optimizer {
+    /// (from, to, token) -> amount
+    amounts: BTreeMap<(Address, Address, Address), U256>,
+    /// (to, token) -> senders
+    incoming: HashMap<(Address, Address), BTreeSet<Address>>,
+    /// (from, token) -> receivers
+    outgoing: HashMap<(Address, Address), BTreeSet<Address>>,
+    min_amount: U256,
+    report: OptimizationReport,
+}
+
+impl Optimizer {
+    /// Adds a transfer, merging it with a parallel transfer or
+    /// netting it out against a transfer in the opposite direction.
+    fn add(&mut self, from: &Address, to: &Address, token: &Address, amount: U256) {
+        if from == to || amount == U256::from(0) {
+            return;
+        }
+        let opposite = self.amounts.get(&(*to, *from, *token)).copied();
+        if let Some(opposite) = opposite.filter(|opposite| {
+            // Do not net out if only a tiny amount would be left.
+            *opposite == amount
+                || max(*opposite, amount) - min(*opposite, amount) >= self.min_amount
+        }) {
+            self.report.netted_opposite += 1;
+            self.remove(to, from, token);
+            if opposite > amount {
+                self.insert(to, from, token, opposite - amount);
+            } else if amount > opposite {
+                self.insert(from, to, token, amount - opposite);
+            }
+        } else if let Some(existing) = self.amounts.get_mut(&(*from, *to, *token)) {
+            self.report.merged_parallel += 1;
+            *existing += amount;
+        } else {
+            self.insert(from, to, token, amount);
+        }
+    }
+
+    fn insert(&mut self, from: &Address, to: &Address, token: &Address, amount: U256) {
+        self.amounts.insert((*from, *to, *token), amount);
+        self.incoming
+            .entry((*to, *token))
+            .or_default()
+            .insert(*from);
+        self.outgoing
+            .entry((*from, *token))
+            .or_default()
+            .insert(*to);
+    }
+
+    fn remove(&mut self, from: &Address, to: &Address, token: &Address) -> U256 {
+        self.incoming
+            .get_mut(&(*to, *token))
+            .map(|senders| senders.remove(from));
+        self.outgoing
+            .get_mut(&(*from, *token))
+            .map(|receivers| receivers.remove(to));
+        self.amounts.remove(&(*from, *to, *token)).unwrap()
+    }
+
+    /// Returns the given senders or receivers of an intermediary together
+    /// with the amounts they transfer, largest amounts first.
+    fn amounts_via(
+        &self,
+        addresses: Option<&BTreeSet<Address>>,
+        key: impl Fn(&Address) -> (Address, Address, Address),
+    ) -> Amounts {
+        let mut result = addresses
+            .into_iter()
+            .flatten()
+            .map(|address| (*address, self.amounts[&key(address)]))
+            .collect::<Amounts>();
+        result.sort_by_key(|(address, amount)| (Reverse(*amount), *address));
+        result
+    }
+
+    /// Tries to bypass `via` for transfers of `token` and returns the
+    /// (from, to) pairs of the new direct transfers.
+    fn collapse(
+        &mut self,
+        via: &Address,
+        token: &Address,
+        capacity: &impl Fn(&Address, &Address, &Address) -> Option<U256>,
+    ) -> Vec<(Address, Address)> {
+        let senders = self.amounts_via(self.incoming.get(&(*via, *token)), |from| {
+            (*from, *via, *token)
+        });
+        let receivers =
+            self.amounts_via(self.outgoing.get(&(*via, *token)), |to| (*via, *to, *token));
+        if senders.is_empty() || receivers.is_empty() {
+            return vec![];
+        }
+
+        let (direct, remaining_in, remaining_out) = pair_amounts(&senders, &receivers);
+        let steps_before = senders.len() + receivers.len();
+        let steps_after = direct.len() + remaining_in.len() + remaining_out.len();
+        let creates_dust = direct
+            .iter()
+            .map(|(_, _, amount)| amount)
+            .chain(
+                remaining_in
+                    .iter()
+                    .chain(&remaining_out)
+                    .map(|(_, amount)| amount),
+            )
+            .any(|amount| *amount < self.min_amount);
+        let fits_edges = direct.iter().all(|(from, to, amount)| {
+            let sent = self.amounts.get(&(*from, *to, *token)).copied();
+            from == to
+                || capacity(from, to, token)
+                    .is_some_and(|capacity| sent.unwrap_or_default() + *amount <= capacity)
+        });
+        if steps_after >= steps_before || creates_dust || !fits_edges {
+            return vec![];
+        }
+
+        self.report.collapsed_chains += 1;
+        for (from, _) in &senders {
+            self.remove(from, via, token);
+        }
+        for (to, _) in &receivers {
+            self.remove(via, to, token);
+        }
+        for (from, amount) in remaining_in {
+            self.add(&from, via, token, amount);
+        }
+        for (to, amount) in remaining_out {
+            self.add(via, &to, token, amount);
+        }
+        for (from, to, amount) in &direct {
+            self.add(from, to, token, *amount);
+        }
+        direct.into_iter().map(|(from, to, _)| (from, to)).collect()
+    }
+}
+
+/// Pairs the amounts sent to an intermediary with the amounts it sends on.
+/// Pairs with equal amounts are matched first, the rest is split greedily.
+///
+/// Returns the direct transfers and the amounts that still have to go via the intermediary.
+fn pair_amounts(
+    senders: &[(Address, U256)],
+    receivers: &[(Address, U256)],
+) -> (Vec<(Address, Address, U256)>, Amounts, Amounts) {
+    let mut direct = vec![];
+    let mut senders = senders.to_vec();
+    let mut receivers = receivers.to_vec();
+    senders.retain(
+        |(from, amount)| match receivers.iter().position(|(_, a)| a == amount) {
+            Some(i) => {
+                let (to, _) = receivers.remove(i);
+                direct.push((*from, to, *amount));
+                false
+            }
+            None => true,
+        },
+    );
+
+    let (mut i, mut j) = (0, 0);
+    while i < senders.len() && j < receivers.len() {
+        let amount = U256::min(senders[i].1, receivers[j].1);
+        direct.push((senders[i].0, receivers[j].0, amount));
+        senders[i].1 -= amount;
+        receivers[j].1 -= amount;
+        if senders[i].1 == U256::from(0) {
+            i += 1;
+        }
+        if receivers[j].1 == U256::from(0) {
+            j += 1;
+        }
+    }
+    (direct, senders[i..].to_vec(), receivers[j..].to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{addresses, edge};
+
+    fn unlimited(_: &Address, _: &Address, _: &Address) -> Option<U256> {
+        Some(U256::MAX)
+    }
+
+    #[test]
+    fn merge_and_net() {
+        let (a, b, c, _, t, _) = addresses();
+        let (transfers, report) = optimize_transfers(
+            vec![
+                edge(a, b, t, 3),
+                edge(a, b, t, 4),
+                edge(b, a, t, 2),
+                edge(a, c, a, 1),
+            ],
+            U256::from(0),
+            unlimited,
+        );
+        assert_eq!(transfers, vec![edge(a, b, t, 5), edge(a, c, a, 1)]);
+        assert_eq!(report.merged_parallel, 1);
+        assert_eq!(report.netted_opposite, 1);
+        assert_eq!(report.steps_saved(), 2);
+    }
+
+    #[test]
+    fn collapse_chains() {
+        let (a, b, c, d, t, _) = addresses();
+        // a sends 5 t to b, which sends 3 t to c and 2 t to d.
+        let (transfers, report) = optimize_transfers(
+            vec![edge(a, b, t, 5), edge(b, c, t, 3), edge(b, d, t, 2)],
+            U256::from(0),
+            unlimited,
+        );
+        assert_eq!(transfers, vec![edge(a, c, t, 3), edge(a, d, t, 2)]);
+        assert_eq!(report.collapsed_chains, 1);
+        assert_eq!(report.steps_saved(), 1);
+
+        // Partial capacities do not reduce the number of transfers.
+        let chain = vec![edge(a, b, t, 5), edge(b, c, t, 3)];
+        assert_eq!(
+            optimize_transfers(chain.clone(), U256::from(0), unlimited).0,
+            chain
+        );
+
+        // Different tokens cannot be collapsed.
+        let chain = vec![edge(a, b, a, 5), edge(b, c, b, 5)];
+        assert_eq!(
+            optimize_transfers(chain.clone(), U256::from(0), unlimited).0,
+            chain
+        );
+
+        // Splitting would create a transfer of 2.
+        let chain = vec![edge(a, b, t, 5), edge(b, c, t, 3), edge(b, d, t, 2)];
+        let (mut transfers, _) = optimize_transfers(chain.clone(), U256::from(3), unlimited);
+        transfers.sort();
+        assert_eq!(transfers, chain);
+
+        // Longer chains are collapsed completely.
+        let (transfers, _) = optimize_transfers(
+            vec![edge(c, d, t, 4), edge(b, c, t, 4), edge(a, b, t, 4)],
+            U256::from(0),
+            unlimited,
+        );
+        assert_eq!(transfers, vec![edge(a, d, t, 4)]);
+    }
+
+    #[test]
+    fn collapse_only_onto_edges() {
+        let (a, b, c, d, t, _) = addresses();
+        let chain = vec![edge(a, b, t, 5), edge(b, c, t, 3), edge(b, d, t, 2)];
+        let capacities = |c_capacity| {
+            move |from: &Address, to: &Address, token: &Address| match (*from, *to) {
+                (from, to) if from == a && to == c && *token == t => Some(U256::from(c_capacity)),
+                (from, to) if from == a && to == d && *token == t => Some(U256::from(2)),
+                _ => None,
+            }
+        };
+        let (transfers, report) = optimize_transfers(chain.clone(), U256::from(0), capacities(3));
+        assert_eq!(transfers, vec![edge(a, c, t, 3), edge(a, d, t, 2)]);
+        assert_eq!(report.collapsed_chains, 1);
+
+        // The edge a -> c is too small.
+        let (mut transfers, report) =
+            optimize_transfers(chain.clone(), U256::from(0), capacities(2));
+        transfers.sort();
+        assert_eq!(transfers, chain);
+        assert_eq!(report.collapsed_chains, 0);
+
+        // There is no edge a -> c at all.
+        let (mut transfers, _) = optimize_transfers(chain.clone(), U256::from(0), |_, _, _| None);
+        transfers.sort();
+        assert_eq!(transfers, chain);
+    }
+}
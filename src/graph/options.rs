@@ -0,0 +1,156 @@
+use num_bigint::BigUint;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::graph::adjacencies::outgoing;
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::{Address, U256};
+
+/// Optional parameters for `compute_flow_with_options`.
+#[derive(Clone, Debug, Default)]
+pub struct FlowOptions {
+    /// Maximum distance (in flow network levels) to explore from the source.
+    pub max_distance: Option<u64>,
+    /// Maximum number of transfers (trust hops) on each path from the source to the sink.
+    /// Unlike `max_distance`, this also holds for the paths of the final result,
+    /// including paths that are rerouted when the search finds more flow.
+    pub max_hops: Option<u64>,
+    /// Maximum number of transfers in the result.
+    pub max_transfers: Option<u64>,
+    /// Which of its tokens the source is allowed to spend, and in which order.
+    pub token_preferences: TokenPreferences,
+    /// Whether to compute the maximum flow or to stop at the requested flow with few transfers.
+    pub objective: Objective,
+    /// If set, edges with a smaller capacity are not used and the result
+    /// does not contain smaller transfers, even if that reduces the flow.
+    pub min_transfer_amount: Option<U256>,
+    /// Reduces the capacities of the edges to account for outdated data.
+    pub capacity_margin: Option<CapacityMargin>,
+    /// If set, at most this percentage of the capacity of each edge that is not
+    /// sent by the source is used. This spreads the flow over more intermediaries
+    /// instead of draining the ones with the highest capacities.
+    pub max_edge_share: Option<u64>,
+    /// If true, computing the transfers fails with `FlowError::InsufficientFlow`
+    /// unless exactly the requested flow can be transferred.
+    pub exact: bool,
+    /// Temporary changes to the edges that are only used for this computation.
+    pub overlay: Option<Arc<EdgeOverlay>>,
+}
+
+/// A safety margin that is subtracted from the capacity of each edge,
+/// so that a transfer does not fail if the capacity changed since the data was retrieved.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CapacityMargin {
+    /// Percentage of the capacity that is not used (0 to 100).
+    pub percent: u64,
+    /// Amount that is subtracted from the capacity (after applying the percentage).
+    pub absolute: U256,
+    /// If set, only edges whose data is older than this are reduced.
+    /// Edges without update time are considered old.
+    pub min_age: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Objective {
+    /// Compute the maximum flow and prune it down to the requested flow.
+    #[default]
+    MaximizeFlow,
+    /// Only search for flow until the requested flow is reached, using
+    /// paths with high capacity and few hops so that few transfers are needed.
+    MinimizeTransfers,
+}
+
+/// Restricts which tokens the source may spend on the first hop
+/// and in which order they are spent.
+#[derive(Clone, Debug, Default)]
+pub struct TokenPreferences {
+    /// If set, the source only spends tokens from this set.
+    pub spend_tokens: Option<BTreeSet<Address>>,
+    /// Tokens the source never spends.
+    pub avoid_tokens: BTreeSet<Address>,
+    pub order: SpendOrder,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpendOrder {
+    /// No preference, all allowed tokens are used at the same time.
+    #[default]
+    Any,
+    /// Spend the source's own token before any received tokens.
+    OwnTokenFirst,
+    /// Spend received tokens before the source's own token.
+    ReceivedTokensFirst,
+}
+
+impl CapacityMargin {
+    /// Returns the reduced capacity of an edge whose data was updated at `updated_at`.
+    pub fn apply(&self, capacity: U256, updated_at: Option<SystemTime>, now: SystemTime) -> U256 {
+        let is_stale = match (self.min_age, updated_at) {
+            (None, _) | (Some(_), None) => true,
+            (Some(min_age), Some(updated_at)) => {
+                now.duration_since(updated_at).unwrap_or_default() > min_age
+            }
+        };
+        if !is_stale {
+            return capacity;
+        }
+        let reduced = percent_of(capacity, 100 - self.percent.min(100));
+        if reduced > self.absolute {
+            reduced - self.absolute
+        } else {
+            U256::from(0)
+        }
+    }
+}
+
+/// Returns `percent` percent of `amount`, rounded down.
+pub fn percent_of(amount: U256, percent: u64) -> U256 {
+    U256::from_bigint_truncating(BigUint::from(amount) * percent / 100u64)
+}
+
+impl TokenPreferences {
+    pub fn is_restricted(&self) -> bool {
+        self.spend_tokens.is_some()
+            || !self.avoid_tokens.is_empty()
+            || self.order != SpendOrder::Any
+    }
+
+    pub fn allows(&self, token: &Address) -> bool {
+        !self.avoid_tokens.contains(token)
+            && self
+                .spend_tokens
+                .as_ref()
+                .is_none_or(|tokens| tokens.contains(token))
+    }
+
+    /// Returns the sets of tokens the source is allowed to spend in each stage
+    /// of the flow computation. Each stage includes the tokens of the previous stages.
+    /// `None` means that there is no restriction.
+    pub fn spending_stages(
+        &self,
+        source: &Address,
+        edges: &EdgeDB,
+        overlay: Option<&EdgeOverlay>,
+    ) -> Vec<Option<BTreeSet<Address>>> {
+        if !self.is_restricted() {
+            return vec![None];
+        }
+        let allowed = outgoing(edges, overlay, source)
+            .map(|e| e.token)
+            .filter(|token| self.allows(token))
+            .collect::<BTreeSet<_>>();
+        let (own, received): (BTreeSet<_>, BTreeSet<_>) =
+            allowed.iter().partition(|token| *token == source);
+        let first = match self.order {
+            SpendOrder::Any => return vec![Some(allowed)],
+            SpendOrder::OwnTokenFirst => own,
+            SpendOrder::ReceivedTokensFirst => received,
+        };
+        if first.is_empty() || first == allowed {
+            vec![Some(allowed)]
+        } else {
+            vec![Some(first), Some(allowed)]
+        }
+    }
+}
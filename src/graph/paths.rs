@@ -0,0 +1,203 @@
+use crate::graph::hop_limit::HopLimitedFlow;
+use crate::graph::{as_trust_node, node_as_address, Node};
+use crate::types::{Address, Edge, U256};
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+
+/// Decomposes the flow in `used_edges` from `source` to `sink` into paths,
+/// shortest paths first.
+///
+/// Returns the nodes of each path (including source and sink) together with
+/// the flow along the path. Flow that is not on a path from `source` to `sink`
+/// (i.e. circulations) is ignored.
+pub fn decompose_into_paths(
+    source: &Node,
+    sink: &Node,
+    used_edges: &HashMap<Node, HashMap<Node, U256>>,
+) -> Vec<(Vec<Node>, U256)> {
+    let mut remaining = used_edges.clone();
+    let mut paths = Vec::new();
+    while let Some(path) = shortest_path(source, sink, &remaining) {
+        let amount = path
+            .windows(2)
+            .map(|w| remaining[&w[0]][&w[1]])
+            .fold(U256::MAX, min);
+        for w in path.windows(2) {
+            let out = remaining.get_mut(&w[0]).unwrap();
+            *out.get_mut(&w[1]).unwrap() -= amount;
+            if out[&w[1]] == U256::from(0) {
+                out.remove(&w[1]);
+            }
+        }
+        paths.push((path, amount));
+    }
+    paths
+}
+
+fn shortest_path(
+    source: &Node,
+    sink: &Node,
+    used_edges: &HashMap<Node, HashMap<Node, U256>>,
+) -> Option<Vec<Node>> {
+    let mut parents: HashMap<&Node, &Node> = HashMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(source);
+    while let Some(n) = queue.pop_front() {
+        if n == sink {
+            let mut path = vec![sink.clone()];
+            let mut node = sink;
+            while node != source {
+                node = parents[node];
+                path.push(node.clone());
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for (t, capacity) in used_edges.get(n).into_iter().flatten() {
+            if *capacity > U256::from(0) && t != source && !parents.contains_key(t) {
+                parents.insert(t, n);
+                queue.push_back(t);
+            }
+        }
+    }
+    None
+}
+
+/// A path of transfers from the source to the sink, each transferring `amount`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferPath {
+    pub amount: U256,
+    /// The transfers along the path, with `amount` as capacity.
+    pub transfers: Vec<Edge>,
+}
+
+/// Decomposes a list of transfers from `source` to `sink` into paths,
+/// shortest paths first. Transfers that are not on a path from `source`
+/// to `sink` are ignored.
+pub fn decompose_transfers(
+    source: &Address,
+    sink: &Address,
+    transfers: &[Edge],
+) -> Vec<TransferPath> {
+    decompose_into_paths(
+        &Node::Node(*source),
+        &Node::Node(*sink),
+        &transfer_graph(transfers),
+    )
+    .into_iter()
+    .map(|(nodes, amount)| transfer_path(&nodes, amount))
+    .collect()
+}
+
+/// Decomposes a list of transfers from `source` to `sink` into paths with at most
+/// `max_hops` transfers each, shortest paths first. Unlike `decompose_transfers`,
+/// this combines the parts of the transfers differently if the shortest paths
+/// first leave only paths that are too long.
+/// Flow that cannot be sent within `max_hops` transfers is not part of any path.
+pub fn decompose_transfers_with_max_hops(
+    source: &Address,
+    sink: &Address,
+    transfers: &[Edge],
+    max_hops: u64,
+) -> Vec<TransferPath> {
+    let used_edges = transfer_graph(transfers);
+    let mut flow = HopLimitedFlow::default();
+    flow.augment(
+        &Node::Node(*source),
+        &Node::Node(*sink),
+        max_hops,
+        U256::MAX,
+        |node| used_edges.get(node).cloned().unwrap_or_default(),
+    );
+    let mut paths = flow
+        .paths()
+        .iter()
+        .map(|(nodes, amount)| transfer_path(nodes, *amount))
+        .collect::<Vec<_>>();
+    paths.sort_by_key(|path| path.transfers.len());
+    paths
+}
+
+/// Turns the transfers into a graph where each transfer is an edge from the sender to
+/// TrustNode(receiver, token), which is connected to the receiver, so that the paths keep the tokens.
+fn transfer_graph(transfers: &[Edge]) -> HashMap<Node, HashMap<Node, U256>> {
+    let mut used_edges: HashMap<Node, HashMap<Node, U256>> = HashMap::new();
+    for transfer in transfers {
+        let trust_node = Node::TrustNode(transfer.to, transfer.token);
+        *used_edges
+            .entry(Node::Node(transfer.from))
+            .or_default()
+            .entry(trust_node.clone())
+            .or_default() += transfer.capacity;
+        *used_edges
+            .entry(trust_node)
+            .or_default()
+            .entry(Node::Node(transfer.to))
+            .or_default() += transfer.capacity;
+    }
+    used_edges
+}
+
+/// Turns a path in the graph of `transfer_graph` into a `TransferPath`.
+fn transfer_path(nodes: &[Node], amount: U256) -> TransferPath {
+    TransferPath {
+        amount,
+        transfers: nodes
+            .windows(3)
+            .step_by(2)
+            .map(|w| {
+                let (to, token) = as_trust_node(&w[1]);
+                Edge {
+                    from: *node_as_address(&w[0]),
+                    to: *to,
+                    token: *token,
+                    capacity: amount,
+                }
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::compute_flow;
+    use crate::test_utils::{addresses, edge};
+    use crate::types::edge::EdgeDB;
+
+    #[test]
+    fn decompose_transfers_adds_up_to_flow() {
+        let (a, b, c, d, ..) = addresses();
+        let edges = EdgeDB::new(vec![
+            edge(a, b, a, 7),
+            edge(b, d, b, 5),
+            edge(a, c, c, 4),
+            edge(c, d, c, 6),
+            edge(b, c, d, 2),
+        ]);
+        let (flow, transfers) = compute_flow(&a, &d, &edges, U256::MAX, None, None).unwrap();
+        assert_eq!(flow, U256::from(11));
+        let paths = decompose_transfers(&a, &d, &transfers);
+        let total = paths
+            .iter()
+            .fold(U256::from(0), |sum, path| sum + path.amount);
+        assert_eq!(total, flow);
+        for path in &paths {
+            assert_eq!(path.transfers.first().unwrap().from, a);
+            assert_eq!(path.transfers.last().unwrap().to, d);
+            for (transfer, next) in path.transfers.iter().zip(path.transfers.iter().skip(1)) {
+                assert_eq!(transfer.to, next.from);
+            }
+            assert!(path.transfers.iter().all(|t| t.capacity == path.amount));
+        }
+        assert_eq!(paths.len(), 3);
+        // The longest path is the one via b and c.
+        let tokens = paths[2]
+            .transfers
+            .iter()
+            .map(|t| t.token)
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![a, d, c]);
+        assert_eq!(paths[2].amount, U256::from(2));
+    }
+}
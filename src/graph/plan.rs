@@ -0,0 +1,131 @@
+use crate::graph::error::FlowError;
+use crate::graph::flow::compute_flow_with_options;
+use crate::graph::options::FlowOptions;
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::{Address, Edge, U256};
+use std::sync::Arc;
+
+/// Number of rounds in which the capacity is distributed
+/// for `AllocationOrder::Fair`.
+const FAIR_ROUNDS: u128 = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllocationOrder {
+    /// Each payment gets as much capacity as it needs, in the given order.
+    #[default]
+    Sequential,
+    /// The capacity is distributed in rounds, where each payment gets
+    /// an equal share of its amount per round. Anything that is still missing
+    /// after the last round is allocated sequentially.
+    Fair,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Payment {
+    pub from: Address,
+    pub to: Address,
+    pub amount: U256,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlannedPayment {
+    pub flow: U256,
+    pub transfers: Vec<Edge>,
+}
+
+/// Plans several payments on the same network, such that capacity used by one
+/// payment is not used again by another one.
+///
+/// The capacity used so far is removed with an overlay on top of `edges`
+/// (and the overlay of the options, if there is one).
+/// The returned transfer lists stay valid if the payments are executed
+/// one after the other in the given order.
+/// Returns an error if the transfers of a payment cannot be ordered.
+pub fn plan_transfers(
+    payments: &[Payment],
+    edges: &EdgeDB,
+    allocation: AllocationOrder,
+    options: &FlowOptions,
+) -> Result<Vec<PlannedPayment>, FlowError> {
+    let mut used = options.overlay.as_deref().cloned().unwrap_or_default();
+    let mut planned = vec![PlannedPayment::default(); payments.len()];
+
+    if allocation == AllocationOrder::Fair {
+        for round in 0..FAIR_ROUNDS {
+            for (payment, planned) in payments.iter().zip(planned.iter_mut()) {
+                let share = payment.amount * U256::from(round + 1) / U256::from(FAIR_ROUNDS);
+                if share > planned.flow {
+                    let amount = share - planned.flow;
+                    allocate(payment, amount, planned, edges, &mut used, options)?;
+                }
+            }
+        }
+    }
+    for (payment, planned) in payments.iter().zip(planned.iter_mut()) {
+        if payment.amount > planned.flow {
+            let amount = payment.amount - planned.flow;
+            allocate(payment, amount, planned, edges, &mut used, options)?;
+        }
+    }
+    Ok(planned)
+}
+
+fn allocate(
+    payment: &Payment,
+    amount: U256,
+    planned: &mut PlannedPayment,
+    edges: &EdgeDB,
+    used: &mut EdgeOverlay,
+    options: &FlowOptions,
+) -> Result<(), FlowError> {
+    let options = FlowOptions {
+        overlay: Some(Arc::new(used.clone())),
+        ..options.clone()
+    };
+    let (flow, transfers) =
+        compute_flow_with_options(&payment.from, &payment.to, edges, amount, &options)?;
+    used.reduce_capacities(edges, &transfers);
+    planned.flow += flow;
+    planned.transfers.extend(transfers);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{addresses, edge};
+
+    #[test]
+    fn shared_capacity() {
+        let (a, b, c, ..) = addresses();
+        let edges = EdgeDB::new(vec![edge(a, b, a, 10), edge(a, c, a, 10)]);
+        // Both payments share the balance of a.
+        let payments = vec![
+            Payment {
+                from: a,
+                to: b,
+                amount: U256::from(7),
+            },
+            Payment {
+                from: a,
+                to: c,
+                amount: U256::from(7),
+            },
+        ];
+        let flows = |allocation| {
+            plan_transfers(&payments, &edges, allocation, &FlowOptions::default())
+                .unwrap()
+                .into_iter()
+                .map(|p| p.flow)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            flows(AllocationOrder::Sequential),
+            vec![U256::from(7), U256::from(3)]
+        );
+        assert_eq!(
+            flows(AllocationOrder::Fair),
+            vec![U256::from(5), U256::from(5)]
+        );
+    }
+}
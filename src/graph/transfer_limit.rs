@@ -0,0 +1,405 @@
+use crate::graph::adjacencies::Adjacencies;
+use crate::graph::error::FlowError;
+use crate::graph::flow::{dinic_max_flow, IncrementalFlow};
+use crate::graph::optimize::OptimizationReport;
+use crate::graph::options::FlowOptions;
+use crate::graph::Node;
+use crate::types::edge::EdgeDB;
+use crate::types::{Address, Edge, U256};
+use std::cmp::{min, Reverse};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// The time budget of `compute_flow_with_transfer_limit` when it is used
+/// by `compute_flow_with_options` to enforce `max_transfers`.
+pub const DEFAULT_TIME_BUDGET: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransferLimitedFlow {
+    pub flow: U256,
+    pub transfers: Vec<Edge>,
+    /// True if the search finished within the time budget, i.e. the flow
+    /// is the largest one possible, false if it is only the best one found.
+    pub optimal: bool,
+    /// What the optimization of the transfers saved.
+    pub report: OptimizationReport,
+}
+
+/// Computes the largest flow (up to `requested_flow`) that can be executed in
+/// at most `max_transfers` transfers.
+///
+/// The search is a branch and bound over subsets of the edges that lie on a path
+/// from the source to the sink (within the maximum distance): A flow that only uses
+/// `max_transfers` edges needs at most that many transfers (after optimization), and
+/// every transfer corresponds to an edge. Bounds are the max flow using all remaining
+/// edges and the flow found so far plus the capacities of the largest remaining edges
+/// that can still be added.
+///
+/// `time_budget` covers the whole function, but the initial max flow computation is
+/// always completed. If the search does not finish in time, the best flow found so far
+/// is returned with `optimal` set to false. The heuristic reduction of the max flow to
+/// `max_transfers` (as in `compute_flow`) is used as starting point.
+///
+/// Returns an error if the transfers of the max flow cannot be ordered,
+/// or if `options.exact` is set and the requested flow does not fit into `max_transfers` steps.
+pub fn compute_flow_with_transfer_limit(
+    source: &Address,
+    sink: &Address,
+    edges: &EdgeDB,
+    requested_flow: U256,
+    max_transfers: u64,
+    time_budget: Duration,
+    options: &FlowOptions,
+) -> Result<TransferLimitedFlow, FlowError> {
+    let deadline = Instant::now() + time_budget;
+    let exact = options.exact;
+    let options = FlowOptions {
+        max_transfers: Some(max_transfers),
+        exact: false,
+        ..options.clone()
+    };
+
+    let mut computation = IncrementalFlow::new(source, sink, edges, requested_flow, &options);
+    computation.extend(options.max_distance);
+    let max_flow = min(computation.flow(), requested_flow);
+    if exact && max_flow != requested_flow {
+        return Err(FlowError::InsufficientFlow {
+            requested: requested_flow,
+            available: max_flow,
+        });
+    }
+    let heuristic = computation.transfers_with_report()?;
+    let fits = heuristic.1.len() as u64 <= max_transfers;
+    if fits && heuristic.0 == max_flow {
+        let (flow, transfers, report) = heuristic;
+        return Ok(TransferLimitedFlow {
+            flow,
+            transfers,
+            optimal: true,
+            report,
+        });
+    }
+
+    let mut search = Search {
+        source: *source,
+        sink: *sink,
+        requested_flow: max_flow,
+        max_edges: max_transfers as usize,
+        candidates: candidate_edges(source, sink, edges, &options),
+        edges,
+        adjacencies: Adjacencies::with_options(edges, source, &options),
+        options: FlowOptions {
+            max_transfers: None,
+            ..options
+        },
+        deadline,
+        timed_out: false,
+        best: if fits {
+            heuristic
+        } else {
+            (U256::from(0), vec![], OptimizationReport::default())
+        },
+    };
+    search.run(&mut vec![], 0, U256::from(0));
+    if exact && search.best.0 != requested_flow {
+        return Err(FlowError::InsufficientFlow {
+            requested: requested_flow,
+            available: search.best.0,
+        });
+    }
+    let (flow, transfers, report) = search.best;
+    Ok(TransferLimitedFlow {
+        optimal: !search.timed_out || flow == max_flow,
+        flow,
+        transfers,
+        report,
+    })
+}
+
+/// Returns the edges (with the overlay applied) that are on a path from `source` to `sink`
+/// within the maximum distance and the maximum number of hops, sorted by decreasing capacity.
+fn candidate_edges(
+    source: &Address,
+    sink: &Address,
+    edges: &EdgeDB,
+    options: &FlowOptions,
+) -> Vec<Edge> {
+    let outgoing = |address: &Address| match &options.overlay {
+        Some(overlay) => overlay.outgoing(edges, address),
+        None => edges.outgoing(address).into_iter().copied().collect(),
+    };
+    let incoming = |address: &Address| match &options.overlay {
+        Some(overlay) => overlay.incoming(edges, address),
+        None => edges.incoming(address).into_iter().copied().collect(),
+    };
+    let max_hops = options
+        .max_distance
+        .map(|distance| distance / 3)
+        .into_iter()
+        .chain(options.max_hops)
+        .min();
+    let from_source = hop_distances(source, sink, max_hops, |a| {
+        outgoing(a).into_iter().map(|e| e.to).collect()
+    });
+    let to_sink = hop_distances(sink, source, max_hops, |a| {
+        incoming(a).into_iter().map(|e| e.from).collect()
+    });
+
+    let to_sink = &to_sink;
+    let mut candidates = from_source
+        .iter()
+        .filter(|(address, _)| *address != sink)
+        .flat_map(|(address, hops)| {
+            outgoing(address).into_iter().filter(move |e| {
+                e.to != *source
+                    && to_sink.get(&e.to).is_some_and(|remaining| {
+                        max_hops.is_none_or(|max_hops| hops + 1 + remaining <= max_hops)
+                    })
+            })
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|e| (Reverse(e.capacity), *e));
+    candidates
+}
+
+/// Returns the number of hops from `start` to each address reachable via `neighbors`
+/// within `max_hops`, without continuing the search from `end`.
+fn hop_distances(
+    start: &Address,
+    end: &Address,
+    max_hops: Option<u64>,
+    neighbors: impl Fn(&Address) -> Vec<Address>,
+) -> HashMap<Address, u64> {
+    let mut distances = HashMap::from([(*start, 0)]);
+    let mut queue = VecDeque::from([*start]);
+    while let Some(address) = queue.pop_front() {
+        let hops = distances[&address];
+        if address == *end || max_hops.is_some_and(|max_hops| hops >= max_hops) {
+            continue;
+        }
+        for neighbor in neighbors(&address) {
+            if let Entry::Vacant(entry) = distances.entry(neighbor) {
+                entry.insert(hops + 1);
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    distances
+}
+
+struct Search<'a> {
+    source: Address,
+    sink: Address,
+    requested_flow: U256,
+    max_edges: usize,
+    options: FlowOptions,
+    /// The edges to choose from (with the overlay applied), sorted by decreasing capacity.
+    candidates: Vec<Edge>,
+    /// All edges, of which only the candidates included in a subset are used.
+    edges: &'a EdgeDB,
+    /// The flow network of `edges`, reused for the bounds of all subsets.
+    adjacencies: Adjacencies<'a>,
+    deadline: Instant,
+    timed_out: bool,
+    best: (U256, Vec<Edge>, OptimizationReport),
+}
+
+impl Search<'_> {
+    /// Explores all subsets of `candidates[next..]` that can be added to `included`.
+    /// `included_flow` is the max flow using only the included edges.
+    fn run(&mut self, included: &mut Vec<Edge>, next: usize, included_flow: U256) {
+        if self.best.0 == self.requested_flow {
+            return;
+        }
+        if Instant::now() > self.deadline {
+            self.timed_out = true;
+            return;
+        }
+        if included.len() == self.max_edges || next == self.candidates.len() {
+            return;
+        }
+        let free = self.max_edges - included.len();
+        let remaining = &self.candidates[next..];
+        let additional = remaining
+            .iter()
+            .take(free)
+            .fold(U256::from(0), |sum, e| sum + e.capacity);
+        if included_flow + additional <= self.best.0 {
+            return;
+        }
+        let all = mask(included.iter().chain(remaining));
+        if self.max_flow(all) <= self.best.0 {
+            return;
+        }
+
+        included.push(self.candidates[next]);
+        let flow = self.max_flow(mask(included.iter()));
+        if flow > self.best.0 {
+            let mut computation = IncrementalFlow::new(
+                &self.source,
+                &self.sink,
+                self.edges,
+                self.requested_flow,
+                &self.options,
+            );
+            computation.restrict_edges(mask(included.iter()));
+            computation.extend(self.options.max_distance);
+            // Subsets whose transfers cannot be ordered are not considered.
+            if let Ok((flow, transfers, report)) = computation.transfers_with_report() {
+                if transfers.len() <= self.max_edges && flow > self.best.0 {
+                    self.best = (flow, transfers, report);
+                }
+            }
+        }
+        self.run(included, next + 1, flow);
+        included.pop();
+
+        self.run(included, next + 1, included_flow);
+    }
+
+    /// Returns the max flow using only the given edges, capped at the requested flow.
+    fn max_flow(&mut self, edges: HashSet<(Address, Address, Address)>) -> U256 {
+        self.adjacencies.restrict_edges(Some(edges));
+        let flow = dinic_max_flow(
+            &mut self.adjacencies,
+            &Node::Node(self.source),
+            &Node::Node(self.sink),
+            self.options.max_distance,
+            &mut HashMap::new(),
+        );
+        min(flow, self.requested_flow)
+    }
+}
+
+/// Returns the keys (from, to, token) of the edges, to restrict a flow network to them.
+fn mask<'e>(edges: impl Iterator<Item = &'e Edge>) -> HashSet<(Address, Address, Address)> {
+    edges.map(|e| (e.from, e.to, e.token)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::options::CapacityMargin;
+    use crate::test_utils::{addresses, edge};
+    use std::time::SystemTime;
+
+    #[test]
+    fn finds_best_flow_within_transfer_limit() {
+        let (a, b, c, d, t, ..) = addresses();
+        // A path of two transfers with capacity 6 and three direct
+        // transfers with capacity 4 each.
+        let edges = EdgeDB::new(vec![
+            edge(a, b, a, 6),
+            edge(b, d, b, 6),
+            edge(a, d, c, 4),
+            edge(a, d, t, 4),
+            edge(a, d, d, 4),
+        ]);
+        let unlimited = compute_flow_with_transfer_limit(
+            &a,
+            &d,
+            &edges,
+            U256::MAX,
+            5,
+            Duration::from_secs(10),
+            &FlowOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(unlimited.flow, U256::from(18));
+        assert!(unlimited.optimal);
+
+        let limited = compute_flow_with_transfer_limit(
+            &a,
+            &d,
+            &edges,
+            U256::MAX,
+            2,
+            Duration::from_secs(10),
+            &FlowOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(limited.flow, U256::from(8));
+        assert_eq!(limited.transfers.len(), 2);
+        assert!(limited.optimal);
+
+        let limited = compute_flow_with_transfer_limit(
+            &a,
+            &d,
+            &edges,
+            U256::MAX,
+            3,
+            Duration::from_secs(10),
+            &FlowOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(limited.flow, U256::from(12));
+        assert!(limited.optimal);
+    }
+
+    #[test]
+    fn search_keeps_update_times() {
+        let (a, b, c, d, t, ..) = addresses();
+        let mut edges = EdgeDB::new(vec![
+            edge(a, b, a, 6),
+            edge(b, d, b, 6),
+            edge(a, d, c, 4),
+            edge(a, d, t, 4),
+            edge(a, d, d, 4),
+        ]);
+        edges.set_updated_at(SystemTime::now());
+        // All edges are recent, so the margin does not apply to any of them.
+        let options = FlowOptions {
+            capacity_margin: Some(CapacityMargin {
+                percent: 50,
+                min_age: Some(Duration::from_secs(3600)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let limited = compute_flow_with_transfer_limit(
+            &a,
+            &d,
+            &edges,
+            U256::MAX,
+            2,
+            Duration::from_secs(10),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(limited.flow, U256::from(8));
+        assert!(limited.optimal);
+    }
+
+    #[test]
+    fn candidates_include_edges_unused_by_the_max_flow() {
+        let (a, b, c, d, t, x) = addresses();
+        // The direct transfer and the path via b share the trust limit of d in t,
+        // so the max flow only uses the direct edge.
+        let edges = EdgeDB::new(vec![
+            edge(a, d, t, 5),
+            edge(a, b, a, 5),
+            edge(b, d, t, 5),
+            // Leaving the paths from a to d or returning to a.
+            edge(b, c, b, 5),
+            edge(d, a, d, 5),
+            edge(a, x, a, 1),
+        ]);
+        let options = FlowOptions::default();
+        let mut computation = IncrementalFlow::new(&a, &d, &edges, U256::MAX, &options);
+        computation.extend(None);
+        assert_eq!(computation.used_edges(), vec![edge(a, d, t, 5)]);
+        assert_eq!(
+            candidate_edges(&a, &d, &edges, &options),
+            vec![edge(a, b, a, 5), edge(a, d, t, 5), edge(b, d, t, 5)]
+        );
+        let options = FlowOptions {
+            max_hops: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            candidate_edges(&a, &d, &edges, &options),
+            vec![edge(a, d, t, 5)]
+        );
+    }
+}
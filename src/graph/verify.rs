@@ -0,0 +1,403 @@
+use crate::graph::adjacencies::{incoming, outgoing};
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::{Address, Edge, U256};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// The first problem `verify_transfers` found in a list of transfers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransferViolation {
+    /// There is no edge for the transfer.
+    UnknownEdge {
+        from: Address,
+        to: Address,
+        token: Address,
+    },
+    /// The transfers from `from` to `to` in `token` add up to more than the capacity.
+    ExceedsCapacity {
+        from: Address,
+        to: Address,
+        token: Address,
+        amount: U256,
+        capacity: U256,
+    },
+    /// The transfers sent by `address` in `token` add up to more than its balance,
+    /// the largest capacity of its edges in that token.
+    ExceedsBalance {
+        address: Address,
+        token: Address,
+        amount: U256,
+        balance: U256,
+    },
+    /// The transfers received by `address` in `token` add up to more than its trust limit,
+    /// the largest capacity of the edges it receives in that token.
+    ExceedsTrustLimit {
+        address: Address,
+        token: Address,
+        amount: U256,
+        trust_limit: U256,
+    },
+    /// The source is also the sink, so there is nothing to transfer.
+    SourceIsSink(Address),
+    /// The transfer with the given index makes its sender send more than it received so far.
+    SpentBeforeReceived { step: usize, address: Address },
+    /// An intermediary does not send on exactly what it receives.
+    NotConserved {
+        address: Address,
+        received: U256,
+        sent: U256,
+    },
+    /// The sink receives a different amount than the flow that is reported for the transfers.
+    WrongAmount { reported: U256, received: U256 },
+}
+
+impl Error for TransferViolation {}
+
+impl Display for TransferViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferViolation::UnknownEdge { from, to, token } => {
+                write!(f, "There is no edge {from} -> {to} in token {token}")
+            }
+            TransferViolation::ExceedsCapacity {
+                from,
+                to,
+                token,
+                amount,
+                capacity,
+            } => write!(
+                f,
+                "Transfers {from} -> {to} in token {token} add up to {} but the capacity is {}",
+                amount.to_decimal(),
+                capacity.to_decimal()
+            ),
+            TransferViolation::ExceedsBalance {
+                address,
+                token,
+                amount,
+                balance,
+            } => write!(
+                f,
+                "{address} sends {} in token {token} but its balance is {}",
+                amount.to_decimal(),
+                balance.to_decimal()
+            ),
+            TransferViolation::ExceedsTrustLimit {
+                address,
+                token,
+                amount,
+                trust_limit,
+            } => write!(
+                f,
+                "{address} receives {} in token {token} but its trust limit is {}",
+                amount.to_decimal(),
+                trust_limit.to_decimal()
+            ),
+            TransferViolation::SourceIsSink(address) => {
+                write!(f, "{address} is both the source and the sink")
+            }
+            TransferViolation::SpentBeforeReceived { step, address } => write!(
+                f,
+                "In step {step}, {address} sends more than it received before"
+            ),
+            TransferViolation::NotConserved {
+                address,
+                received,
+                sent,
+            } => write!(
+                f,
+                "{address} receives {} but sends {}",
+                received.to_decimal(),
+                sent.to_decimal()
+            ),
+            TransferViolation::WrongAmount { reported, received } => write!(
+                f,
+                "The sink receives {} but the reported flow is {}",
+                received.to_decimal(),
+                reported.to_decimal()
+            ),
+        }
+    }
+}
+
+/// Checks that `transfers` can be executed in the given order on `edges`
+/// (with the changes of `overlay` applied) and returns the amount they transfer
+/// from `source` to `sink`:
+///
+/// * The transfers between the same addresses in the same token together fit the capacity
+///   of their edge.
+/// * All transfers of a sender in a token together fit its balance, and all transfers
+///   to a receiver in a token (except its own) together fit its trust limit.
+/// * Every address except the source only sends what it received in earlier transfers.
+/// * Every address except the source and the sink sends on exactly what it receives,
+///   so the sink receives what the source sends.
+///
+/// If the source is the sink, only an empty list of transfers is valid.
+pub fn verify_transfers(
+    edges: &EdgeDB,
+    overlay: Option<&EdgeOverlay>,
+    source: &Address,
+    sink: &Address,
+    transfers: &[Edge],
+) -> Result<U256, TransferViolation> {
+    if source == sink {
+        return if transfers.is_empty() {
+            Ok(U256::from(0))
+        } else {
+            Err(TransferViolation::SourceIsSink(*source))
+        };
+    }
+    let mut aggregated = BTreeMap::<(Address, Address, Address), U256>::new();
+    let mut sent_in_token = BTreeMap::<(Address, Address), U256>::new();
+    let mut received_in_token = BTreeMap::<(Address, Address), U256>::new();
+    for transfer in transfers {
+        *aggregated
+            .entry((transfer.from, transfer.to, transfer.token))
+            .or_default() += transfer.capacity;
+        *sent_in_token
+            .entry((transfer.from, transfer.token))
+            .or_default() += transfer.capacity;
+        if transfer.to != transfer.token {
+            *received_in_token
+                .entry((transfer.to, transfer.token))
+                .or_default() += transfer.capacity;
+        }
+    }
+    for ((from, to, token), amount) in aggregated {
+        let capacity = outgoing(edges, overlay, &from)
+            .find(|e| e.to == to && e.token == token)
+            .ok_or(TransferViolation::UnknownEdge { from, to, token })?
+            .capacity;
+        if amount > capacity {
+            return Err(TransferViolation::ExceedsCapacity {
+                from,
+                to,
+                token,
+                amount,
+                capacity,
+            });
+        }
+    }
+    // Every sender and receiver has an edge in the token, otherwise the check above fails.
+    for ((address, token), amount) in sent_in_token {
+        let balance = largest_capacity(outgoing(edges, overlay, &address), &token);
+        if amount > balance {
+            return Err(TransferViolation::ExceedsBalance {
+                address,
+                token,
+                amount,
+                balance,
+            });
+        }
+    }
+    for ((address, token), amount) in received_in_token {
+        let trust_limit = largest_capacity(incoming(edges, overlay, &address), &token);
+        if amount > trust_limit {
+            return Err(TransferViolation::ExceedsTrustLimit {
+                address,
+                token,
+                amount,
+                trust_limit,
+            });
+        }
+    }
+
+    let mut received = HashMap::<Address, U256>::new();
+    let mut sent = HashMap::<Address, U256>::new();
+    for (step, transfer) in transfers.iter().enumerate() {
+        let total_sent = sent.entry(transfer.from).or_default();
+        *total_sent += transfer.capacity;
+        if transfer.from != *source
+            && *total_sent > received.get(&transfer.from).copied().unwrap_or_default()
+        {
+            return Err(TransferViolation::SpentBeforeReceived {
+                step,
+                address: transfer.from,
+            });
+        }
+        *received.entry(transfer.to).or_default() += transfer.capacity;
+    }
+
+    for (address, received) in &received {
+        let sent = sent.get(address).copied().unwrap_or_default();
+        if address != source && address != sink && *received != sent {
+            return Err(TransferViolation::NotConserved {
+                address: *address,
+                received: *received,
+                sent,
+            });
+        }
+    }
+    // Since flow is conserved everywhere else, this is also what the source sends.
+    // The sink cannot send more than it received, see above.
+    Ok(received.get(sink).copied().unwrap_or_default()
+        - sent.get(sink).copied().unwrap_or_default())
+}
+
+/// Returns the largest capacity of the edges in `token`.
+fn largest_capacity(edges: impl Iterator<Item = Edge>, token: &Address) -> U256 {
+    edges
+        .filter(|e| e.token == *token)
+        .map(|e| e.capacity)
+        .max()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph::compute_flow;
+    use crate::test_utils::{addresses, edge};
+
+    #[test]
+    fn verify() {
+        let (a, b, c, d, ..) = addresses();
+        let edges = EdgeDB::new(vec![
+            edge(a, b, a, 7),
+            edge(b, d, b, 5),
+            edge(a, c, c, 4),
+            edge(c, d, c, 6),
+            edge(b, c, d, 2),
+        ]);
+        let (flow, transfers) = compute_flow(&a, &d, &edges, U256::MAX, None, None).unwrap();
+        assert_eq!(verify_transfers(&edges, None, &a, &d, &transfers), Ok(flow));
+
+        // The transfers via c in c's token cannot bypass c, there is no edge a -> d.
+        assert_eq!(
+            verify_transfers(&edges, None, &a, &d, &[edge(a, d, c, 4)]),
+            Err(TransferViolation::UnknownEdge {
+                from: a,
+                to: d,
+                token: c
+            })
+        );
+        assert_eq!(
+            verify_transfers(&edges, None, &a, &d, &[edge(a, d, b, 4)]),
+            Err(TransferViolation::UnknownEdge {
+                from: a,
+                to: d,
+                token: b
+            })
+        );
+        assert_eq!(
+            verify_transfers(
+                &edges,
+                None,
+                &a,
+                &d,
+                &[edge(a, b, a, 4), edge(b, d, b, 2), edge(a, b, a, 4)]
+            ),
+            Err(TransferViolation::ExceedsCapacity {
+                from: a,
+                to: b,
+                token: a,
+                amount: U256::from(8),
+                capacity: U256::from(7)
+            })
+        );
+        assert_eq!(
+            verify_transfers(&edges, None, &a, &d, &[edge(b, d, b, 4), edge(a, b, a, 4)]),
+            Err(TransferViolation::SpentBeforeReceived {
+                step: 0,
+                address: b
+            })
+        );
+        assert_eq!(
+            verify_transfers(&edges, None, &a, &d, &[edge(a, b, a, 4), edge(b, d, b, 3)]),
+            Err(TransferViolation::NotConserved {
+                address: b,
+                received: U256::from(4),
+                sent: U256::from(3)
+            })
+        );
+        assert_eq!(
+            verify_transfers(&edges, None, &a, &c, &[edge(a, b, a, 4), edge(b, d, b, 4)]),
+            Err(TransferViolation::NotConserved {
+                address: d,
+                received: U256::from(4),
+                sent: U256::from(0)
+            })
+        );
+    }
+
+    #[test]
+    fn verify_aggregates() {
+        let (a, b, c, d, t, ..) = addresses();
+        // a holds 7 of its token, b and c trust it for 7 each.
+        let edges = EdgeDB::new(vec![
+            edge(a, b, a, 7),
+            edge(a, c, a, 7),
+            edge(b, d, b, 7),
+            edge(c, d, c, 7),
+        ]);
+        assert_eq!(
+            verify_transfers(
+                &edges,
+                None,
+                &a,
+                &d,
+                &[
+                    edge(a, b, a, 7),
+                    edge(a, c, a, 7),
+                    edge(b, d, b, 7),
+                    edge(c, d, c, 7)
+                ]
+            ),
+            Err(TransferViolation::ExceedsBalance {
+                address: a,
+                token: a,
+                amount: U256::from(14),
+                balance: U256::from(7)
+            })
+        );
+
+        // b accepts at most 5 of t, from a or from c.
+        let edges = EdgeDB::new(vec![edge(a, c, c, 5), edge(c, b, t, 5), edge(a, b, t, 5)]);
+        assert_eq!(
+            verify_transfers(
+                &edges,
+                None,
+                &a,
+                &b,
+                &[edge(a, c, c, 5), edge(c, b, t, 5), edge(a, b, t, 5)]
+            ),
+            Err(TransferViolation::ExceedsTrustLimit {
+                address: b,
+                token: t,
+                amount: U256::from(10),
+                trust_limit: U256::from(5)
+            })
+        );
+    }
+
+    #[test]
+    fn verify_with_overlay_and_same_address() {
+        let (a, b, ..) = addresses();
+        let edges = EdgeDB::new(vec![edge(a, b, a, 7)]);
+        let overlay = EdgeOverlay::new(vec![edge(a, b, a, 3)]);
+        assert_eq!(
+            verify_transfers(&edges, None, &a, &b, &[edge(a, b, a, 5)]),
+            Ok(U256::from(5))
+        );
+        assert_eq!(
+            verify_transfers(&edges, Some(&overlay), &a, &b, &[edge(a, b, a, 5)]),
+            Err(TransferViolation::ExceedsCapacity {
+                from: a,
+                to: b,
+                token: a,
+                amount: U256::from(5),
+                capacity: U256::from(3)
+            })
+        );
+
+        assert_eq!(
+            verify_transfers(&edges, None, &a, &a, &[]),
+            Ok(U256::from(0))
+        );
+        assert_eq!(
+            verify_transfers(&edges, None, &a, &a, &[edge(a, b, a, 5)]),
+            Err(TransferViolation::SourceIsSink(a))
+        );
+    }
+}
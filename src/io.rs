@@ -34,10 +34,9 @@ pub fn read_edges_csv(path: &String) -> Result<EdgeDB, io::Error> {
                 });
             }
             _ => {
-                return Result::Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Expected from,to,token,capacity, but got {line}"),
-                ))
+                return Result::Err(io::Error::other(format!(
+                    "Expected from,to,token,capacity, but got {line}"
+                )))
             }
         }
     }
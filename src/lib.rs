@@ -1,5 +1,9 @@
 pub mod graph;
 pub mod io;
+pub mod reservations;
 pub mod safe_db;
 pub mod server;
+pub mod stats;
+#[cfg(test)]
+mod test_utils;
 pub mod types;
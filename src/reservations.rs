@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::Edge;
+
+/// Capacity held for a computed transfer until it is executed.
+#[derive(Clone, Debug)]
+pub struct Reservation {
+    pub transfers: Vec<Edge>,
+    pub expires_at: Instant,
+}
+
+/// Keeps track of the capacities used by computed but not yet executed transfers,
+/// so that later computations do not use the same capacity again.
+#[derive(Debug, Default)]
+pub struct Reservations {
+    next_id: u64,
+    holds: BTreeMap<u64, Reservation>,
+}
+
+impl Reservations {
+    /// Holds the capacities used by `transfers` for `ttl` and returns the id of the reservation,
+    /// or `None` if `ttl` is too large to compute the time the reservation expires.
+    pub fn reserve(&mut self, transfers: Vec<Edge>, ttl: Duration) -> Option<u64> {
+        let expires_at = Instant::now().checked_add(ttl)?;
+        self.next_id += 1;
+        self.holds.insert(
+            self.next_id,
+            Reservation {
+                transfers,
+                expires_at,
+            },
+        );
+        Some(self.next_id)
+    }
+
+    /// Releases the held capacities without applying them.
+    pub fn release(&mut self, id: u64) -> Option<Reservation> {
+        self.holds.remove(&id)
+    }
+
+    /// Removes the reservation and returns its transfers, which
+    /// should then be applied to the edges permanently.
+    pub fn confirm(&mut self, id: u64) -> Option<Vec<Edge>> {
+        self.holds.remove(&id).map(|r| r.transfers)
+    }
+
+    pub fn remove_expired(&mut self, now: Instant) {
+        self.holds.retain(|_, r| r.expires_at > now);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.holds.is_empty()
+    }
+
+    /// Returns the transfers of all held reservations.
+    pub fn transfers(&self) -> Vec<Edge> {
+        self.holds
+            .values()
+            .flat_map(|r| r.transfers.iter().copied())
+            .collect()
+    }
+
+    /// Returns the changes that remove the capacities of all held reservations from `edges`.
+    pub fn overlay(&self, edges: &EdgeDB) -> EdgeOverlay {
+        let mut overlay = EdgeOverlay::default();
+        overlay.reduce_capacities(edges, &self.transfers());
+        overlay
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{addresses, edge};
+
+    #[test]
+    fn reserve_release_expire() {
+        let (a, b, c, ..) = addresses();
+        let edges = EdgeDB::new(vec![edge(a, b, a, 10), edge(a, c, a, 8)]);
+        let mut reservations = Reservations::default();
+        let first = reservations
+            .reserve(vec![edge(a, b, a, 3)], Duration::from_secs(60))
+            .unwrap();
+        let second = reservations
+            .reserve(vec![edge(a, b, a, 4)], Duration::from_secs(1))
+            .unwrap();
+        // The reservations also reduce the other edges sending a's balance.
+        let overlay = reservations.overlay(&edges);
+        assert_eq!(
+            overlay.outgoing(&edges, &a),
+            vec![edge(a, b, a, 3), edge(a, c, a, 1)]
+        );
+
+        assert!(reservations.release(first).is_some());
+        assert!(reservations.release(first).is_none());
+        let overlay = reservations.overlay(&edges);
+        assert_eq!(
+            overlay.outgoing(&edges, &a),
+            vec![edge(a, b, a, 6), edge(a, c, a, 4)]
+        );
+
+        reservations.remove_expired(Instant::now() + Duration::from_secs(2));
+        assert!(reservations.is_empty());
+        assert!(reservations.confirm(second).is_none());
+    }
+
+    #[test]
+    fn held_on_top_of_overlay() {
+        let (a, b, ..) = addresses();
+        let edges = EdgeDB::new(vec![edge(a, b, a, 10)]);
+        let mut reservations = Reservations::default();
+        reservations
+            .reserve(vec![edge(a, b, a, 6)], Duration::from_secs(60))
+            .unwrap();
+        assert!(reservations
+            .reserve(vec![edge(a, b, a, 1)], Duration::MAX)
+            .is_none());
+        // An overlay that raises the capacity does not restore the held capacity.
+        let mut overlay = EdgeOverlay::new(vec![edge(a, b, a, 8)]);
+        overlay.reduce_capacities(&edges, &reservations.transfers());
+        assert_eq!(overlay.outgoing(&edges, &a), vec![edge(a, b, a, 2)]);
+    }
+}
@@ -1,6 +1,24 @@
 use std::collections::BTreeMap;
 
-use crate::types::{edge::EdgeDB, Address, Edge, Safe, U256};
+use crate::types::edge::{EdgeDB, EdgeOverlay};
+use crate::types::{Address, Edge, Safe, U256};
+
+/// A change to a safe that is not applied to the database, but used for what-if queries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SafeChange {
+    /// `user` sets the trust in `send_to` to `percentage` (zero removes the trust).
+    Trust {
+        user: Address,
+        send_to: Address,
+        percentage: u8,
+    },
+    /// The balance of `user` in `token` changes to `balance`.
+    Balance {
+        user: Address,
+        token: Address,
+        balance: U256,
+    },
+}
 
 #[derive(Default, Debug)]
 pub struct DB {
@@ -29,43 +47,207 @@ impl DB {
         &self.edges
     }
 
+    /// Returns the changes to the edges that result from the given changes to the safes,
+    /// without modifying the database. The result can be used as overlay of `edges()`.
+    pub fn edge_overlay(&self, changes: &[SafeChange]) -> EdgeOverlay {
+        let mut changed = BTreeMap::<Address, Safe>::new();
+        for change in changes {
+            let user = match change {
+                SafeChange::Trust { user, .. } | SafeChange::Balance { user, .. } => user,
+            };
+            let safe = changed
+                .entry(*user)
+                .or_insert_with(|| self.safes.get(user).cloned().unwrap_or_default());
+            match change {
+                SafeChange::Trust {
+                    send_to,
+                    percentage: 0,
+                    ..
+                } => {
+                    safe.limit_percentage.remove(send_to);
+                }
+                SafeChange::Trust {
+                    send_to,
+                    percentage,
+                    ..
+                } => {
+                    safe.limit_percentage.insert(*send_to, *percentage);
+                }
+                SafeChange::Balance { token, balance, .. } => {
+                    safe.balances.insert(*token, *balance);
+                }
+            }
+        }
+        let safe = |address: &Address| changed.get(address).or_else(|| self.safes.get(address));
+
+        let mut overlay = EdgeOverlay::default();
+        // The edges sent by a changed safe depend on its trust and balances, the trust edges
+        // it receives depend on its balances. Remove all of them and add the new ones.
+        let senders = self
+            .safes
+            .iter()
+            .filter(|(_, s)| s.limit_percentage.keys().any(|to| changed.contains_key(to)))
+            .map(|(user, _)| user);
+        for sender in senders.chain(changed.keys()) {
+            for edge in self.edges.outgoing(sender) {
+                if changed.contains_key(&edge.from) || changed.contains_key(&edge.to) {
+                    overlay.set(Edge {
+                        capacity: U256::from(0),
+                        ..*edge
+                    });
+                }
+            }
+            if let Some(sender_safe) = safe(sender) {
+                for edge in self.edges_of(sender, sender_safe, safe) {
+                    if changed.contains_key(&edge.from) || changed.contains_key(&edge.to) {
+                        overlay.set(edge);
+                    }
+                }
+            }
+        }
+        overlay
+    }
+
     fn compute_edges(&mut self) {
         let mut edges = vec![];
         for (user, safe) in &self.safes {
-            // trust connections
-            for (send_to, percentage) in &safe.limit_percentage {
-                if *user == *send_to {
-                    continue;
+            edges.extend(self.edges_of(user, safe, |address| self.safes.get(address)));
+        }
+        self.edges = EdgeDB::new(edges)
+    }
+
+    /// Returns the edges sent by `user`, whose safe is `safe`. Other safes are looked up with `safe_of`.
+    fn edges_of<'a>(
+        &self,
+        user: &Address,
+        safe: &Safe,
+        safe_of: impl Fn(&Address) -> Option<&'a Safe>,
+    ) -> Vec<Edge> {
+        let mut edges = vec![];
+        // trust connections
+        for (send_to, percentage) in &safe.limit_percentage {
+            if *user == *send_to {
+                continue;
+            }
+            if let Some(receiver_safe) = safe_of(send_to) {
+                // TODO should return "limited or not"
+                // edge should contain token balance and transfer limit (which can be unlimited)
+                let limit = safe.trust_transfer_limit(receiver_safe, *percentage);
+                if limit != U256::from(0) {
+                    edges.push(Edge {
+                        from: *user,
+                        to: *send_to,
+                        token: *user,
+                        capacity: limit,
+                    })
                 }
-                if let Some(receiver_safe) = self.safes.get(send_to) {
-                    // TODO should return "limited or not"
-                    // edge should contain token balance and transfer limit (which can be unlimited)
-                    let limit = safe.trust_transfer_limit(receiver_safe, *percentage);
-                    if limit != U256::from(0) {
-                        edges.push(Edge {
-                            from: *user,
-                            to: *send_to,
-                            token: *user,
-                            capacity: limit,
-                        })
-                    }
+            }
+        }
+        // send tokens back to owner
+        for (token, balance) in &safe.balances {
+            if let Some(owner) = self.token_owner.get(token) {
+                if *user != *owner && *balance != U256::from(0) {
+                    edges.push(Edge {
+                        from: *user,
+                        to: *owner,
+                        token: *owner,
+                        // TODO capacity should be only limited by own balance.
+                        capacity: *balance,
+                    })
+                }
+            }
+        }
+        edges
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn edge_overlay_matches_changed_db() {
+        let addresses = (1..=6)
+            .map(|i| Address::from(format!("0x{i:040x}").as_str()))
+            .collect::<Vec<_>>();
+        let (users, tokens) = addresses.split_at(3);
+        let safes = |changes: &[SafeChange]| {
+            let mut safes = BTreeMap::new();
+            for (i, user) in users.iter().enumerate() {
+                let mut safe = Safe {
+                    token_address: tokens[i],
+                    ..Default::default()
+                };
+                for (j, token) in tokens.iter().enumerate() {
+                    safe.balances
+                        .insert(*token, U256::from((10 * (i + 1) + j) as u128));
                 }
+                for other in users {
+                    safe.limit_percentage.insert(*other, 50);
+                }
+                safes.insert(*user, safe);
             }
-            // send tokens back to owner
-            for (token, balance) in &safe.balances {
-                if let Some(owner) = self.token_owner.get(token) {
-                    if *user != *owner && *balance != U256::from(0) {
-                        edges.push(Edge {
-                            from: *user,
-                            to: *owner,
-                            token: *owner,
-                            // TODO capacity should be only limited by own balance.
-                            capacity: *balance,
-                        })
+            for change in changes {
+                match change {
+                    SafeChange::Trust {
+                        user,
+                        send_to,
+                        percentage,
+                    } => {
+                        safes
+                            .get_mut(user)
+                            .unwrap()
+                            .limit_percentage
+                            .insert(*send_to, *percentage);
+                    }
+                    SafeChange::Balance {
+                        user,
+                        token,
+                        balance,
+                    } => {
+                        safes
+                            .get_mut(user)
+                            .unwrap()
+                            .balances
+                            .insert(*token, *balance);
                     }
                 }
             }
+            let token_owner = users.iter().zip(tokens).map(|(u, t)| (*t, *u)).collect();
+            DB::new(safes, token_owner)
+        };
+        let changes = vec![
+            SafeChange::Trust {
+                user: users[0],
+                send_to: users[2],
+                percentage: 100,
+            },
+            SafeChange::Balance {
+                user: users[1],
+                token: tokens[0],
+                balance: U256::from(0),
+            },
+            SafeChange::Balance {
+                user: users[2],
+                token: tokens[2],
+                balance: U256::from(1000),
+            },
+        ];
+        let db = safes(&[]);
+        let overlay = db.edge_overlay(&changes);
+        let changed = safes(&changes);
+        assert_ne!(db.edges().edges(), changed.edges().edges());
+        for user in users {
+            let mut expected = changed
+                .edges()
+                .outgoing(user)
+                .into_iter()
+                .copied()
+                .collect::<Vec<_>>();
+            expected.sort();
+            let mut actual = overlay.outgoing(db.edges(), user);
+            actual.sort();
+            assert_eq!(actual, expected);
         }
-        self.edges = EdgeDB::new(edges)
     }
 }
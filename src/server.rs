@@ -1,10 +1,18 @@
 use crate::graph;
+use crate::graph::{
+    compute_flow_with_transfer_limit, AllocationOrder, BatchLimits, CapacityMargin, FlowError,
+    FlowOptions, IncrementalFlow, Liquidities, Objective, Payment, SpendOrder, TokenPreferences,
+    TransferViolation,
+};
 use crate::io::{import_from_safes_binary, read_edges_binary, read_edges_csv};
-use crate::types::edge::EdgeDB;
+use crate::reservations::Reservations;
+use crate::stats;
+use crate::types::edge::{EdgeDB, EdgeOverlay};
 use crate::types::{Address, Edge, U256};
 use json::JsonValue;
 use num_bigint::BigUint;
 use regex::Regex;
+use std::collections::BTreeSet;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::io::Read;
@@ -15,6 +23,18 @@ use std::str::FromStr;
 use std::sync::mpsc::TrySendError;
 use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The largest number of solutions `compute_alternatives` computes, since each one
+/// is a full flow computation.
+const MAX_ALTERNATIVES: usize = 10;
+
+/// The longest time (in seconds) `compute_transfer` can reserve capacity for.
+const MAX_RESERVE_SECONDS: u64 = 60 * 60;
+
+/// The edges and reservations in which `compute_transfer` reserves
+/// the capacity of its final result, and for how long.
+type ReservationTarget<'a> = (&'a RwLock<Arc<EdgeDB>>, &'a Mutex<Reservations>, Duration);
 
 struct JsonRpcRequest {
     id: JsonValue,
@@ -70,15 +90,17 @@ fn validate_and_parse_u256(value_str: &str) -> Result<U256, Box<dyn Error>> {
 
 pub fn start_server(listen_at: &str, queue_size: usize, threads: u64) {
     let edges: Arc<RwLock<Arc<EdgeDB>>> = Arc::new(RwLock::new(Arc::new(EdgeDB::default())));
+    let reservations: Arc<Mutex<Reservations>> = Arc::new(Mutex::new(Reservations::default()));
 
     let (sender, receiver) = mpsc::sync_channel(queue_size);
     let protected_receiver = Arc::new(Mutex::new(receiver));
     for _ in 0..threads {
         let rec = protected_receiver.clone();
         let e = edges.clone();
+        let r = reservations.clone();
         thread::spawn(move || loop {
             let socket = rec.lock().unwrap().recv().unwrap();
-            if let Err(e) = handle_connection(e.deref(), socket) {
+            if let Err(e) = handle_connection(e.deref(), r.deref(), socket) {
                 println!("Error handling connection: {e}");
             }
         });
@@ -102,6 +124,7 @@ pub fn start_server(listen_at: &str, queue_size: usize, threads: u64) {
 
 fn handle_connection(
     edges: &RwLock<Arc<EdgeDB>>,
+    reservations: &Mutex<Reservations>,
     mut socket: TcpStream,
 ) -> Result<(), Box<dyn Error>> {
     let request = read_request(&mut socket)?;
@@ -135,8 +158,97 @@ fn handle_connection(
         }
         "compute_transfer" => {
             println!("Computing flow");
+            // The flow is computed without holding the lock, the capacity
+            // is checked again when the reservation is made.
+            let reservation = match parse_reserve_seconds(&request.params) {
+                Ok(ttl) => ttl.map(|ttl| (edges, reservations, ttl)),
+                Err(e) => {
+                    socket.write_all(
+                        jsonrpc_error_response(request.id, error_code(e.as_ref()), &e.to_string())
+                            .as_bytes(),
+                    )?;
+                    return Ok(());
+                }
+            };
+            let (e, held) = available_edges(edges, reservations);
+            compute_transfer(request, e.as_ref(), &held, reservation, socket)?;
+        }
+        "max_receivable" => {
+            let (e, held) = available_edges(edges, reservations);
+            compute_max_receivable(request, e.as_ref(), &held, socket)?;
+        }
+        "reachable" => {
+            let (e, held) = available_edges(edges, reservations);
+            compute_reachable(request, e.as_ref(), &held, socket)?;
+        }
+        "release_reservation" => {
+            let response = match request.params["id"].as_u64() {
+                Some(id) => jsonrpc_response(
+                    request.id,
+                    reservations.lock().unwrap().release(id).is_some(),
+                ),
+                None => {
+                    jsonrpc_error_response(request.id, -32602, "Invalid arguments: Expected id.")
+                }
+            };
+            socket.write_all(response.as_bytes())?;
+        }
+        "confirm_reservation" => {
+            let response = match request.params["id"].as_u64() {
+                Some(id) => match confirm_reservation(edges, reservations, id) {
+                    Ok(len) => jsonrpc_response(request.id, len),
+                    Err(e) => jsonrpc_error_response(request.id, -32000, &e.to_string()),
+                },
+                None => {
+                    jsonrpc_error_response(request.id, -32602, "Invalid arguments: Expected id.")
+                }
+            };
+            socket.write_all(response.as_bytes())?;
+        }
+        "compute_multi_transfer" => {
+            let (e, held) = available_edges(edges, reservations);
+            let response = match compute_multi_transfer(&request.params, e.as_ref(), &held) {
+                Ok(result) => jsonrpc_response(request.id, result),
+                Err(e) => {
+                    jsonrpc_error_response(request.id, error_code(e.as_ref()), &e.to_string())
+                }
+            };
+            socket.write_all(response.as_bytes())?;
+        }
+        "graph_stats" => {
             let e = edges.read().unwrap().clone();
-            compute_transfer(request, e.as_ref(), socket)?;
+            let stats = stats::edge_stats(e.as_ref());
+            socket.write_all(jsonrpc_response(request.id, stats.to_json()).as_bytes())?;
+        }
+        "cut_tree" => {
+            let (e, held) = available_edges(edges, reservations);
+            let response = match compute_cut_tree(&request.params, e.as_ref(), &held) {
+                Ok(result) => jsonrpc_response(request.id, result),
+                Err(e) => {
+                    jsonrpc_error_response(request.id, error_code(e.as_ref()), &e.to_string())
+                }
+            };
+            socket.write_all(response.as_bytes())?;
+        }
+        "plan_transfers" => {
+            let (e, held) = available_edges(edges, reservations);
+            let response = match plan_transfers(&request.params, e.as_ref(), &held) {
+                Ok(result) => jsonrpc_response(request.id, result),
+                Err(e) => {
+                    jsonrpc_error_response(request.id, error_code(e.as_ref()), &e.to_string())
+                }
+            };
+            socket.write_all(response.as_bytes())?;
+        }
+        "compute_alternatives" => {
+            let (e, held) = available_edges(edges, reservations);
+            let response = match compute_alternatives(&request.params, e.as_ref(), &held) {
+                Ok(result) => jsonrpc_response(request.id, result),
+                Err(e) => {
+                    jsonrpc_error_response(request.id, error_code(e.as_ref()), &e.to_string())
+                }
+            };
+            socket.write_all(response.as_bytes())?;
         }
         "update_edges" => {
             let response = match request.params {
@@ -161,29 +273,138 @@ fn handle_connection(
 }
 
 fn load_edges_binary(edges: &RwLock<Arc<EdgeDB>>, file: &String) -> Result<usize, Box<dyn Error>> {
-    let updated_edges = read_edges_binary(file)?;
+    let mut updated_edges = read_edges_binary(file)?;
+    updated_edges.set_updated_at(std::fs::metadata(file)?.modified()?);
     let len = updated_edges.edge_count();
     *edges.write().unwrap() = Arc::new(updated_edges);
     Ok(len)
 }
 
 fn load_edges_csv(edges: &RwLock<Arc<EdgeDB>>, file: &String) -> Result<usize, Box<dyn Error>> {
-    let updated_edges = read_edges_csv(file)?;
+    let mut updated_edges = read_edges_csv(file)?;
+    updated_edges.set_updated_at(std::fs::metadata(file)?.modified()?);
     let len = updated_edges.edge_count();
     *edges.write().unwrap() = Arc::new(updated_edges);
     Ok(len)
 }
 
 fn load_safes_binary(edges: &RwLock<Arc<EdgeDB>>, file: &str) -> Result<usize, Box<dyn Error>> {
-    let updated_edges = import_from_safes_binary(file)?.edges().clone();
+    let mut updated_edges = import_from_safes_binary(file)?.edges().clone();
+    updated_edges.set_updated_at(std::fs::metadata(file)?.modified()?);
     let len = updated_edges.edge_count();
     *edges.write().unwrap() = Arc::new(updated_edges);
     Ok(len)
 }
 
+/// Parses the time to reserve the capacity of a transfer for, which
+/// has to be between one second and `MAX_RESERVE_SECONDS`. Reservations are
+/// only made on the real edges, so they cannot be combined with `overlay_edges`.
+fn parse_reserve_seconds(params: &JsonValue) -> Result<Option<Duration>, Box<dyn Error>> {
+    let value = &params["reserve_seconds"];
+    if value.is_null() {
+        return Ok(None);
+    }
+    if !params["overlay_edges"].is_null() {
+        return Err(Box::new(InputValidationError(
+            "reserve_seconds cannot be combined with overlay_edges.".to_string(),
+        )));
+    }
+    match value.as_u64() {
+        Some(seconds) if (1..=MAX_RESERVE_SECONDS).contains(&seconds) => {
+            Ok(Some(Duration::from_secs(seconds)))
+        }
+        _ => Err(Box::new(InputValidationError(format!(
+            "Invalid reserve_seconds: {value}. Expected an integer from 1 to {MAX_RESERVE_SECONDS}."
+        )))),
+    }
+}
+
+/// Returns the current edges and the transfers of the reservations, whose capacities are held.
+fn available_edges(
+    edges: &RwLock<Arc<EdgeDB>>,
+    reservations: &Mutex<Reservations>,
+) -> (Arc<EdgeDB>, Vec<Edge>) {
+    // Both locks are taken in the same order as in `confirm_reservation`,
+    // so that a confirmed reservation is either in the edges or in the held transfers.
+    let e = edges.read().unwrap();
+    let mut held = reservations.lock().unwrap();
+    held.remove_expired(Instant::now());
+    (e.clone(), held.transfers())
+}
+
+/// Returns the overlay of the request with the capacities held by reservations removed
+/// on top of it, so that the overlay cannot make held capacity available again.
+fn with_held(
+    edges: &EdgeDB,
+    held: &[Edge],
+    overlay: Option<Arc<EdgeOverlay>>,
+) -> Option<Arc<EdgeOverlay>> {
+    if held.is_empty() {
+        return overlay;
+    }
+    let mut combined = overlay.map(|o| o.as_ref().clone()).unwrap_or_default();
+    combined.reduce_capacities(edges, held);
+    Some(Arc::new(combined))
+}
+
+/// Holds the capacity used by `transfers` for `ttl` and returns the id of the reservation.
+/// The transfers were computed on a snapshot, so they are first checked against the current
+/// edges and reservations, in case another request reserved the same capacity in the meantime.
+fn reserve(
+    edges: &RwLock<Arc<EdgeDB>>,
+    reservations: &Mutex<Reservations>,
+    ttl: Duration,
+    from: &Address,
+    to: &Address,
+    transfers: Vec<Edge>,
+) -> Result<u64, String> {
+    let e = edges.read().unwrap();
+    let mut held = reservations.lock().unwrap();
+    held.remove_expired(Instant::now());
+    let available = held.overlay(e.as_ref());
+    graph::verify_transfers(e.as_ref(), Some(&available), from, to, &transfers).map_err(|v| {
+        format!("Error: The capacity was reserved by another request in the meantime: {v}")
+    })?;
+    held.reserve(transfers, ttl)
+        .ok_or_else(|| format!("Error: Invalid reservation time of {ttl:?}"))
+}
+
+fn reservation_error(id: JsonValue, message: &str) -> String {
+    chunked_response(&(jsonrpc_error(id, -32000, message) + "\r\n"))
+}
+
+/// Applies the transfers of the reservation to the edges and removes it,
+/// with both locked so that other requests see either the one or the other.
+fn confirm_reservation(
+    edges: &RwLock<Arc<EdgeDB>>,
+    reservations: &Mutex<Reservations>,
+    id: u64,
+) -> Result<usize, Box<dyn Error>> {
+    let mut e = edges.write().unwrap();
+    let mut held = reservations.lock().unwrap();
+    held.remove_expired(Instant::now());
+    let transfers = held
+        .confirm(id)
+        .ok_or_else(|| InputValidationError(format!("Unknown or expired reservation: {id}")))?;
+    let mut reduced = EdgeOverlay::default();
+    reduced.reduce_capacities(e.as_ref(), &transfers);
+    let mut updating_edges = e.as_ref().clone();
+    let now = SystemTime::now();
+    for edge in reduced.changes() {
+        updating_edges.update_at(edge, now);
+    }
+    let len = updating_edges.edge_count();
+    *e = Arc::new(updating_edges);
+    Ok(len)
+}
+
+/// Computes the transfer on `edges` with the capacities held by reservations (`held`)
+/// removed. With `reservation`, the capacity of the final result is reserved.
 fn compute_transfer(
     request: JsonRpcRequest,
     edges: &EdgeDB,
+    held: &[Edge],
+    reservation: Option<ReservationTarget>,
     mut socket: TcpStream,
 ) -> Result<(), Box<dyn Error>> {
     socket.write_all(chunked_header().as_bytes())?;
@@ -196,46 +417,688 @@ fn compute_transfer(
     let from_address = validate_and_parse_ethereum_address(&request.params["from"].to_string())?;
     let to_address = validate_and_parse_ethereum_address(&request.params["to"].to_string())?;
 
-    let max_distances = if request.params["iterative"].as_bool().unwrap_or_default() {
-        vec![Some(1), Some(2), None]
-    } else {
-        vec![None]
+    let max_distances = match &request.params["distance_schedule"] {
+        JsonValue::Null => {
+            if request.params["iterative"].as_bool().unwrap_or_default() {
+                vec![Some(1), Some(2), None]
+            } else {
+                vec![None]
+            }
+        }
+        JsonValue::Array(schedule) if !schedule.is_empty() => schedule
+            .iter()
+            .map(|d| match d {
+                JsonValue::Null => Ok(None),
+                _ => d.as_u64().map(Some).ok_or_else(|| {
+                    InputValidationError(format!("Invalid distance in distance_schedule: {d}"))
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        other => {
+            return Err(Box::new(InputValidationError(format!(
+                "Expected non-empty array as distance_schedule, but got {other}"
+            ))))
+        }
     };
 
-    let max_transfers = request.params["max_transfers"].as_u64();
-    for max_distance in max_distances {
-        let (flow, transfers) = graph::compute_flow(
+    let mut options = parse_flow_options(&request.params)?;
+    // The overlay of the request, which also applies when the reservation is made.
+    options.overlay = with_held(edges, held, options.overlay);
+    let steps_json = match request.params["output"].as_str() {
+        None | Some("list") => transfer_steps_json,
+        Some("dag") => transfer_dag_json,
+        Some(other) => {
+            return Err(Box::new(InputValidationError(format!(
+                "Invalid output: {other}. Expected one of list, dag."
+            ))))
+        }
+    };
+    let batch_limits = parse_batch_limits(&request.params)?;
+    let with_paths = request.params["paths"].as_bool().unwrap_or_default();
+    // The options that apply to the final result, also used to explain a zero flow.
+    let final_options = FlowOptions {
+        max_distance: *max_distances.last().unwrap(),
+        ..options.clone()
+    };
+    // The heuristic reduction to max_transfers is only used if asked for.
+    if request.params["optimize_transfers"]
+        .as_bool()
+        .unwrap_or(options.max_transfers.is_some())
+    {
+        let max_transfers = options.max_transfers.ok_or_else(|| {
+            InputValidationError("optimize_transfers requires max_transfers".to_string())
+        })?;
+        let time_budget = request.params["time_budget_ms"]
+            .as_u64()
+            .map_or(graph::DEFAULT_TIME_BUDGET, Duration::from_millis);
+        let limited = compute_flow_with_transfer_limit(
             &from_address,
             &to_address,
             edges,
             parsed_value_param,
-            max_distance,
             max_transfers,
+            time_budget,
+            &final_options,
         );
+        let limited = limited.and_then(|limited| {
+            verify_result(
+                edges,
+                options.overlay.as_deref(),
+                &from_address,
+                &to_address,
+                &limited.transfers,
+                limited.flow,
+            )?;
+            let batches = batches_json(&from_address, &limited.transfers, &batch_limits)?;
+            Ok((limited, batches))
+        });
+        let (limited, batches) = match limited {
+            Ok(result) => result,
+            Err(e) => {
+                socket.write_all(chunked_flow_error(request.id, &e).as_bytes())?;
+                socket.write_all(chunked_close().as_bytes())?;
+                return Ok(());
+            }
+        };
+        let mut result = json::object! {
+            maxFlowValue: limited.flow.to_decimal(),
+            final: true,
+            optimal: limited.optimal,
+            transferSteps: steps_json(&limited.transfers),
+            optimization: limited.report.to_json(),
+        };
+        if !batches.is_null() {
+            result["batches"] = batches;
+        }
+        if with_paths {
+            result["paths"] = paths_json(
+                &from_address,
+                &to_address,
+                &limited.transfers,
+                options.max_hops,
+            )
+            .into();
+        }
+        if limited.flow == U256::from(0) {
+            result["diagnostics"] =
+                graph::diagnose_zero_flow(&from_address, &to_address, edges, &final_options)
+                    .to_json();
+        }
+        if let Some((edges, reservations, ttl)) = reservation {
+            match reserve(
+                edges,
+                reservations,
+                ttl,
+                &from_address,
+                &to_address,
+                limited.transfers,
+            ) {
+                Ok(id) => result["reservationId"] = id.into(),
+                Err(message) => {
+                    socket.write_all(reservation_error(request.id, &message).as_bytes())?;
+                    socket.write_all(chunked_close().as_bytes())?;
+                    return Ok(());
+                }
+            }
+        }
+        socket.write_all(
+            chunked_response(&(jsonrpc_result(request.id.clone(), result) + "\r\n")).as_bytes(),
+        )?;
+        socket.write_all(chunked_close().as_bytes())?;
+        return Ok(());
+    }
+    let mut computation = IncrementalFlow::new(
+        &from_address,
+        &to_address,
+        edges,
+        parsed_value_param,
+        &options,
+    );
+    for (i, max_distance) in max_distances.iter().enumerate() {
+        computation.extend(*max_distance);
+        // Stop early if the requested value is already reached.
+        let is_final = i + 1 == max_distances.len() || computation.is_complete();
+        // Partial results are not sent in exact mode.
+        if options.exact && !is_final {
+            continue;
+        }
+        let computed = computation
+            .transfers_with_report()
+            .and_then(|(flow, transfers, report)| {
+                verify_result(
+                    edges,
+                    options.overlay.as_deref(),
+                    &from_address,
+                    &to_address,
+                    &transfers,
+                    flow,
+                )?;
+                let batches = batches_json(&from_address, &transfers, &batch_limits)?;
+                Ok((flow, transfers, report, batches))
+            });
+        let (flow, transfers, report, batches) = match computed {
+            Ok(result) => result,
+            Err(e) => {
+                socket.write_all(chunked_flow_error(request.id.clone(), &e).as_bytes())?;
+                break;
+            }
+        };
         println!("Computed flow with max distance {max_distance:?}: {flow}");
+        let mut result = json::object! {
+            maxFlowValue: flow.to_decimal(),
+            final: is_final,
+            transferSteps: steps_json(&transfers),
+            optimization: report.to_json(),
+        };
+        if !batches.is_null() {
+            result["batches"] = batches;
+        }
+        if with_paths {
+            result["paths"] =
+                paths_json(&from_address, &to_address, &transfers, options.max_hops).into();
+        }
+        if is_final && flow == U256::from(0) {
+            result["diagnostics"] =
+                graph::diagnose_zero_flow(&from_address, &to_address, edges, &final_options)
+                    .to_json();
+        }
+        if let (true, Some((edges, reservations, ttl))) = (is_final, reservation) {
+            match reserve(
+                edges,
+                reservations,
+                ttl,
+                &from_address,
+                &to_address,
+                transfers,
+            ) {
+                Ok(id) => result["reservationId"] = id.into(),
+                Err(message) => {
+                    socket.write_all(reservation_error(request.id.clone(), &message).as_bytes())?;
+                    break;
+                }
+            }
+        }
         socket.write_all(
-            chunked_response(
-                &(jsonrpc_result(
-                    request.id.clone(),
-                    json::object! {
-                        maxFlowValue: flow.to_decimal(),
-                        final: max_distance.is_none(),
-                        transferSteps: transfers.into_iter().map(|e| json::object! {
-                            from: e.from.to_checksummed_hex(),
-                            to: e.to.to_checksummed_hex(),
-                            token_owner: e.token.to_checksummed_hex(),
-                            value: e.capacity.to_decimal(),
-                        }).collect::<Vec<_>>(),
-                    },
-                ) + "\r\n"),
-            )
-            .as_bytes(),
+            chunked_response(&(jsonrpc_result(request.id.clone(), result) + "\r\n")).as_bytes(),
+        )?;
+        if is_final {
+            break;
+        }
+    }
+    socket.write_all(chunked_close().as_bytes())?;
+    Ok(())
+}
+
+/// Streams the addresses that can send to `to` with the maximum amounts,
+/// largest first, one result per chunk.
+fn compute_max_receivable(
+    request: JsonRpcRequest,
+    edges: &EdgeDB,
+    held: &[Edge],
+    socket: TcpStream,
+) -> Result<(), Box<dyn Error>> {
+    let to_address = validate_and_parse_ethereum_address(&request.params["to"].to_string())?;
+    let max_hops = request.params["max_hops"].as_u64().unwrap_or(3);
+    let liquidities =
+        graph::max_receivable(&to_address, edges, with_held(edges, held, None), max_hops);
+    stream_liquidities(request, liquidities, socket)
+}
+
+/// Streams the addresses `from` can send to with upper bounds
+/// (or the exact maximum amounts), largest first, one result per chunk.
+fn compute_reachable(
+    request: JsonRpcRequest,
+    edges: &EdgeDB,
+    held: &[Edge],
+    socket: TcpStream,
+) -> Result<(), Box<dyn Error>> {
+    let from_address = validate_and_parse_ethereum_address(&request.params["from"].to_string())?;
+    let max_hops = request.params["max_hops"].as_u64().unwrap_or(3);
+    let exact = request.params["exact"].as_bool().unwrap_or_default();
+    let liquidities = graph::reachable(
+        &from_address,
+        edges,
+        with_held(edges, held, None),
+        max_hops,
+        exact,
+    );
+    stream_liquidities(request, liquidities, socket)
+}
+
+fn stream_liquidities(
+    request: JsonRpcRequest,
+    liquidities: Liquidities,
+    mut socket: TcpStream,
+) -> Result<(), Box<dyn Error>> {
+    let limit = request.params["limit"].as_usize().unwrap_or(usize::MAX);
+    socket.write_all(chunked_header().as_bytes())?;
+    for liquidity in liquidities.take(limit) {
+        let result = json::object! {
+            address: liquidity.address.to_checksummed_hex(),
+            value: liquidity.amount.to_decimal(),
+            exact: liquidity.exact,
+        };
+        socket.write_all(
+            chunked_response(&(jsonrpc_result(request.id.clone(), result) + "\r\n")).as_bytes(),
         )?;
     }
     socket.write_all(chunked_close().as_bytes())?;
     Ok(())
 }
 
+fn compute_cut_tree(
+    params: &JsonValue,
+    edges: &EdgeDB,
+    held: &[Edge],
+) -> Result<JsonValue, Box<dyn Error>> {
+    let addresses = parse_address_list(&params["addresses"])?
+        .into_iter()
+        .collect::<Vec<_>>();
+    if addresses.len() < 2 {
+        return Err(Box::new(InputValidationError(
+            "Expected at least two addresses.".to_string(),
+        )));
+    }
+    Ok(graph::CutTree::new(
+        &addresses,
+        edges,
+        with_held(edges, held, None),
+        params["max_distance"].as_u64(),
+    )
+    .to_json())
+}
+
+fn compute_multi_transfer(
+    params: &JsonValue,
+    edges: &EdgeDB,
+    held: &[Edge],
+) -> Result<JsonValue, Box<dyn Error>> {
+    let sources = params["sources"]
+        .members()
+        .map(|source| {
+            let address = validate_and_parse_ethereum_address(&source["address"].to_string())?;
+            let limit = match source["limit"].as_str() {
+                Some(limit) => Some(validate_and_parse_u256(limit)?),
+                None => None,
+            };
+            Ok((address, limit))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    let sinks = params["sinks"]
+        .members()
+        .map(|sink| {
+            let address = validate_and_parse_ethereum_address(&sink["address"].to_string())?;
+            let value = match sink["value"].as_str() {
+                Some(value) => validate_and_parse_u256(value)?,
+                None => U256::MAX,
+            };
+            Ok((address, value))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    if sources.is_empty() || sinks.is_empty() {
+        return Err(Box::new(InputValidationError(
+            "Expected non-empty arrays \"sources\" and \"sinks\".".to_string(),
+        )));
+    }
+
+    let overlay = with_held(edges, held, None);
+    let (flow, attributed) = graph::compute_multi_flow(
+        &sources,
+        &sinks,
+        edges,
+        overlay.clone(),
+        params["max_distance"].as_u64(),
+    )?;
+    for t in &attributed {
+        verify_result(
+            edges,
+            overlay.as_deref(),
+            &t.source,
+            &t.sink,
+            &t.transfers,
+            t.flow,
+        )?;
+    }
+    Ok(json::object! {
+        maxFlowValue: flow.to_decimal(),
+        transfers: attributed.into_iter().map(|t| json::object! {
+            from: t.source.to_checksummed_hex(),
+            to: t.sink.to_checksummed_hex(),
+            value: t.flow.to_decimal(),
+            transferSteps: transfer_steps_json(&t.transfers),
+        }).collect::<Vec<_>>(),
+    })
+}
+
+fn plan_transfers(
+    params: &JsonValue,
+    edges: &EdgeDB,
+    held: &[Edge],
+) -> Result<JsonValue, Box<dyn Error>> {
+    let payments = params["payments"]
+        .members()
+        .map(|payment| {
+            Ok(Payment {
+                from: validate_and_parse_ethereum_address(&payment["from"].to_string())?,
+                to: validate_and_parse_ethereum_address(&payment["to"].to_string())?,
+                amount: validate_and_parse_u256(&payment["value"].to_string())?,
+            })
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    let allocation = match params["allocation"].as_str() {
+        None | Some("sequential") => AllocationOrder::Sequential,
+        Some("fair") => AllocationOrder::Fair,
+        Some(other) => {
+            return Err(Box::new(InputValidationError(format!(
+                "Invalid allocation: {other}. Expected one of sequential, fair."
+            ))))
+        }
+    };
+    let mut options = parse_flow_options(params)?;
+    options.max_distance = params["max_distance"].as_u64();
+    options.overlay = with_held(edges, held, options.overlay);
+
+    let planned = graph::plan_transfers(&payments, edges, allocation, &options)?;
+    for (payment, planned) in payments.iter().zip(&planned) {
+        verify_result(
+            edges,
+            options.overlay.as_deref(),
+            &payment.from,
+            &payment.to,
+            &planned.transfers,
+            planned.flow,
+        )?;
+    }
+    Ok(json::object! {
+        payments: payments.iter().zip(planned).map(|(payment, planned)| json::object! {
+            from: payment.from.to_checksummed_hex(),
+            to: payment.to.to_checksummed_hex(),
+            maxFlowValue: planned.flow.to_decimal(),
+            transferSteps: transfer_steps_json(&planned.transfers),
+        }).collect::<Vec<_>>(),
+    })
+}
+
+fn compute_alternatives(
+    params: &JsonValue,
+    edges: &EdgeDB,
+    held: &[Edge],
+) -> Result<JsonValue, Box<dyn Error>> {
+    let from_address = validate_and_parse_ethereum_address(&params["from"].to_string())?;
+    let to_address = validate_and_parse_ethereum_address(&params["to"].to_string())?;
+    let value = match params["value"].as_str() {
+        Some(value) => validate_and_parse_u256(value)?,
+        None => U256::MAX,
+    };
+    let count = params["count"]
+        .as_usize()
+        .unwrap_or(3)
+        .min(MAX_ALTERNATIVES);
+    let mut options = parse_flow_options(params)?;
+    options.max_distance = params["max_distance"].as_u64();
+    options.overlay = with_held(edges, held, options.overlay);
+
+    let alternatives =
+        graph::compute_alternatives(&from_address, &to_address, edges, value, count, &options)?;
+    for alternative in &alternatives {
+        verify_result(
+            edges,
+            options.overlay.as_deref(),
+            &from_address,
+            &to_address,
+            &alternative.transfers,
+            alternative.flow,
+        )?;
+    }
+    Ok(json::object! {
+        alternatives: alternatives.iter().map(|alternative| json::object! {
+            maxFlowValue: alternative.flow.to_decimal(),
+            hops: alternative.hops,
+            transferCount: alternative.transfers.len(),
+            intermediaries: alternative.intermediaries.iter().map(|a| a.to_checksummed_hex()).collect::<Vec<_>>(),
+            transferSteps: transfer_steps_json(&alternative.transfers),
+        }).collect::<Vec<_>>(),
+    })
+}
+
+fn transfer_steps_json(transfers: &[Edge]) -> Vec<JsonValue> {
+    transfers
+        .iter()
+        .map(|e| {
+            json::object! {
+                from: e.from.to_checksummed_hex(),
+                to: e.to.to_checksummed_hex(),
+                token_owner: e.token.to_checksummed_hex(),
+                value: e.capacity.to_decimal(),
+            }
+        })
+        .collect()
+}
+
+/// Decomposes the transfers into paths from `from` to `to`, each with its
+/// value and the steps along the path (which all transfer that value).
+/// With `max_hops`, no path has more than `max_hops` steps.
+fn paths_json(
+    from: &Address,
+    to: &Address,
+    transfers: &[Edge],
+    max_hops: Option<u64>,
+) -> Vec<JsonValue> {
+    match max_hops {
+        Some(max_hops) => graph::decompose_transfers_with_max_hops(from, to, transfers, max_hops),
+        None => graph::decompose_transfers(from, to, transfers),
+    }
+    .into_iter()
+    .map(|path| {
+        json::object! {
+            value: path.amount.to_decimal(),
+            steps: path.transfers.iter().map(|e| json::object! {
+                from: e.from.to_checksummed_hex(),
+                to: e.to.to_checksummed_hex(),
+                token_owner: e.token.to_checksummed_hex(),
+            }).collect::<Vec<_>>(),
+        }
+    })
+    .collect()
+}
+
+/// Like `transfer_steps_json`, but each step also contains its index
+/// and the indices of the steps that have to be executed before it.
+fn transfer_dag_json(transfers: &[Edge]) -> Vec<JsonValue> {
+    transfer_steps_json(transfers)
+        .into_iter()
+        .zip(graph::transfer_dependencies(transfers))
+        .enumerate()
+        .map(|(i, (mut step, depends_on))| {
+            step["step"] = i.into();
+            step["dependsOn"] = depends_on.into();
+            step
+        })
+        .collect()
+}
+
+fn parse_batch_limits(params: &JsonValue) -> Result<Option<BatchLimits>, Box<dyn Error>> {
+    let max_steps = params["max_batch_steps"].as_usize();
+    let max_gas = params["max_batch_gas"].as_u64();
+    if max_steps.is_none() && max_gas.is_none() {
+        return Ok(None);
+    }
+    let gas_per_step = params["gas_per_step"].as_u64();
+    if max_gas.is_some() && gas_per_step.is_none() {
+        return Err(Box::new(InputValidationError(
+            "max_batch_gas requires gas_per_step".to_string(),
+        )));
+    }
+    Ok(Some(BatchLimits {
+        max_steps,
+        max_gas,
+        gas_per_step: gas_per_step.unwrap_or_default(),
+        base_gas: params["base_gas"].as_u64().unwrap_or(21000),
+    }))
+}
+
+/// Guards against returning transfers that cannot be executed on `edges` with
+/// the overlay applied or that do not transfer exactly the reported `flow`.
+fn verify_result(
+    edges: &EdgeDB,
+    overlay: Option<&EdgeOverlay>,
+    from: &Address,
+    to: &Address,
+    transfers: &[Edge],
+    flow: U256,
+) -> Result<(), FlowError> {
+    let received = graph::verify_transfers(edges, overlay, from, to, transfers)
+        .map_err(|violation| FlowError::InvalidTransfers(Box::new(violation)))?;
+    if received != flow {
+        return Err(FlowError::InvalidTransfers(Box::new(
+            TransferViolation::WrongAmount {
+                reported: flow,
+                received,
+            },
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the batches of the transfers as arrays of step indices
+/// or null if there are no batch limits.
+fn batches_json(
+    source: &Address,
+    transfers: &[Edge],
+    limits: &Option<BatchLimits>,
+) -> Result<JsonValue, FlowError> {
+    let Some(limits) = limits else {
+        return Ok(JsonValue::Null);
+    };
+    Ok(graph::split_into_batches(source, transfers, limits)?
+        .into_iter()
+        .map(|batch| batch.collect::<Vec<_>>())
+        .collect::<Vec<_>>()
+        .into())
+}
+
+fn parse_flow_options(params: &JsonValue) -> Result<FlowOptions, Box<dyn Error>> {
+    let spend_order = match params["spend_order"].as_str() {
+        None | Some("any") => SpendOrder::Any,
+        Some("own_first") => SpendOrder::OwnTokenFirst,
+        Some("received_first") => SpendOrder::ReceivedTokensFirst,
+        Some(other) => {
+            return Err(Box::new(InputValidationError(format!(
+                "Invalid spend_order: {other}. Expected one of any, own_first, received_first."
+            ))))
+        }
+    };
+    let objective = match params["objective"].as_str() {
+        None | Some("maximize_flow") => Objective::MaximizeFlow,
+        Some("minimize_transfers") => Objective::MinimizeTransfers,
+        Some(other) => {
+            return Err(Box::new(InputValidationError(format!(
+                "Invalid objective: {other}. Expected one of maximize_flow, minimize_transfers."
+            ))))
+        }
+    };
+    let max_edge_share = match &params["max_edge_share_percent"] {
+        JsonValue::Null => None,
+        share => match share.as_u64() {
+            Some(percent) if percent <= 100 => Some(percent),
+            _ => {
+                return Err(Box::new(InputValidationError(format!(
+                "Invalid max_edge_share_percent: {share}. Expected an integer between 0 and 100."
+            ))))
+            }
+        },
+    };
+    let min_transfer_amount = match params["min_transfer_amount"].as_str() {
+        Some(amount) => Some(validate_and_parse_u256(amount)?),
+        None => None,
+    };
+    Ok(FlowOptions {
+        max_hops: params["max_hops"].as_u64(),
+        max_transfers: params["max_transfers"].as_u64(),
+        objective,
+        min_transfer_amount,
+        capacity_margin: parse_capacity_margin(params)?,
+        max_edge_share,
+        exact: params["exact"].as_bool().unwrap_or_default(),
+        overlay: parse_overlay(&params["overlay_edges"])?.map(Arc::new),
+        token_preferences: TokenPreferences {
+            spend_tokens: if params["spend_tokens"].is_null() {
+                None
+            } else {
+                Some(parse_address_list(&params["spend_tokens"])?)
+            },
+            avoid_tokens: parse_address_list(&params["avoid_tokens"])?,
+            order: spend_order,
+        },
+        ..Default::default()
+    })
+}
+
+fn parse_overlay(value: &JsonValue) -> Result<Option<EdgeOverlay>, Box<dyn Error>> {
+    let changes = match value {
+        JsonValue::Null => return Ok(None),
+        JsonValue::Array(changes) => changes,
+        _ => {
+            return Err(Box::new(InputValidationError(format!(
+                "Expected array as overlay_edges, but got {value}"
+            ))))
+        }
+    };
+    let changes = changes
+        .iter()
+        .map(|e| {
+            Ok(Edge {
+                from: validate_and_parse_ethereum_address(&e["from"].to_string())?,
+                to: validate_and_parse_ethereum_address(&e["to"].to_string())?,
+                token: validate_and_parse_ethereum_address(&e["token_owner"].to_string())?,
+                capacity: validate_and_parse_u256(&e["capacity"].to_string())?,
+            })
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    Ok(Some(EdgeOverlay::new(changes)))
+}
+
+fn parse_capacity_margin(params: &JsonValue) -> Result<Option<CapacityMargin>, Box<dyn Error>> {
+    let percent = &params["capacity_margin_percent"];
+    let absolute = &params["capacity_margin_absolute"];
+    if percent.is_null() && absolute.is_null() {
+        return Ok(None);
+    }
+    let percent = match percent.as_u64() {
+        Some(percent) if percent <= 100 => percent,
+        None if percent.is_null() => 0,
+        _ => {
+            return Err(Box::new(InputValidationError(format!(
+                "Invalid capacity_margin_percent: {percent}. Expected an integer between 0 and 100."
+            ))))
+        }
+    };
+    let absolute = match absolute.as_str() {
+        Some(amount) => validate_and_parse_u256(amount)?,
+        None => U256::from(0),
+    };
+    Ok(Some(CapacityMargin {
+        percent,
+        absolute,
+        min_age: params["capacity_margin_min_age_seconds"]
+            .as_u64()
+            .map(Duration::from_secs),
+    }))
+}
+
+fn parse_address_list(value: &JsonValue) -> Result<BTreeSet<Address>, Box<dyn Error>> {
+    match value {
+        JsonValue::Null => Ok(BTreeSet::new()),
+        JsonValue::Array(addresses) => addresses
+            .iter()
+            .map(|a| validate_and_parse_ethereum_address(&a.to_string()))
+            .collect(),
+        _ => Err(Box::new(InputValidationError(format!(
+            "Expected array of addresses, but got {value}"
+        )))),
+    }
+}
+
 fn update_edges(
     edges: &RwLock<Arc<EdgeDB>>,
     updates: Vec<JsonValue>,
@@ -254,8 +1117,9 @@ fn update_edges(
     }
 
     let mut updating_edges = edges.read().unwrap().as_ref().clone();
+    let now = SystemTime::now();
     for update in updates {
-        updating_edges.update(update);
+        updating_edges.update_at(update, now);
     }
     let len = updating_edges.edge_count();
     *edges.write().unwrap() = Arc::new(updating_edges);
@@ -317,7 +1181,16 @@ fn jsonrpc_result(id: JsonValue, result: impl Into<json::JsonValue>) -> String {
 }
 
 fn jsonrpc_error_response(id: JsonValue, code: i64, message: &str) -> String {
-    let payload = json::object! {
+    let payload = jsonrpc_error(id, code, message);
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+        payload.len(),
+        payload
+    )
+}
+
+fn jsonrpc_error(id: JsonValue, code: i64, message: &str) -> String {
+    json::object! {
         jsonrpc: "2.0",
         id: id,
         error: {
@@ -325,12 +1198,40 @@ fn jsonrpc_error_response(id: JsonValue, code: i64, message: &str) -> String {
             message: message
         }
     }
-    .dump();
-    format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-        payload.len(),
-        payload
-    )
+    .dump()
+}
+
+/// Returns the error code for an error of a method call:
+/// Errors of the flow computation are processing errors, all others are invalid arguments.
+fn error_code(e: &(dyn Error + 'static)) -> i64 {
+    if e.is::<FlowError>() {
+        -32000
+    } else {
+        -32602
+    }
+}
+
+fn chunked_flow_error(id: JsonValue, e: &FlowError) -> String {
+    let mut error = json::object! {
+        code: -32000,
+        message: e.to_string()
+    };
+    if let FlowError::InsufficientFlow {
+        requested,
+        available,
+    } = e
+    {
+        error["data"] = json::object! {
+            requested: requested.to_decimal(),
+            available: available.to_decimal(),
+        };
+    }
+    let response = json::object! {
+        jsonrpc: "2.0",
+        id: id,
+        error: error
+    };
+    chunked_response(&(response.dump() + "\r\n"))
 }
 
 fn chunked_header() -> String {
@@ -0,0 +1,228 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use json::JsonValue;
+
+use crate::safe_db::db::DB;
+use crate::types::edge::EdgeDB;
+use crate::types::{Address, U256};
+
+/// Statistics about the trust graph.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GraphStats {
+    /// Number of addresses that are the sender or receiver of an edge.
+    pub address_count: usize,
+    pub token_count: usize,
+    pub edge_count: usize,
+    pub zero_capacity_edge_count: usize,
+    /// Number of addresses by number of outgoing edges with non-zero capacity.
+    pub out_degrees: BTreeMap<usize, usize>,
+    /// Number of addresses by number of incoming edges with non-zero capacity.
+    pub in_degrees: BTreeMap<usize, usize>,
+    /// Sizes of the strongly connected components of the graph of
+    /// non-zero capacity edges, largest first.
+    pub component_sizes: Vec<usize>,
+    /// Number of edges by number of decimal digits of their capacity.
+    pub capacity_digits: BTreeMap<usize, usize>,
+    /// Number of safes, only known for statistics of a safe database.
+    pub safe_count: Option<usize>,
+    /// Number of safes without any edge with non-zero capacity.
+    pub isolated_safe_count: Option<usize>,
+}
+
+impl GraphStats {
+    pub fn zero_capacity_share(&self) -> f64 {
+        if self.edge_count == 0 {
+            0.0
+        } else {
+            self.zero_capacity_edge_count as f64 / self.edge_count as f64
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let histogram = |counts: &BTreeMap<usize, usize>| {
+            let mut result = JsonValue::new_object();
+            for (key, count) in counts {
+                result[key.to_string()] = (*count).into();
+            }
+            result
+        };
+        let mut sizes = BTreeMap::<usize, usize>::new();
+        for size in &self.component_sizes {
+            *sizes.entry(*size).or_default() += 1;
+        }
+        json::object! {
+            addresses: self.address_count,
+            tokens: self.token_count,
+            edges: self.edge_count,
+            zeroCapacityEdges: self.zero_capacity_edge_count,
+            zeroCapacityShare: self.zero_capacity_share(),
+            outDegrees: histogram(&self.out_degrees),
+            inDegrees: histogram(&self.in_degrees),
+            components: {
+                count: self.component_sizes.len(),
+                largest: self.component_sizes.first().copied().unwrap_or_default(),
+                sizes: histogram(&sizes),
+            },
+            capacityDigits: histogram(&self.capacity_digits),
+            safes: self.safe_count,
+            isolatedSafes: self.isolated_safe_count,
+        }
+    }
+}
+
+/// Computes the statistics of an edge database.
+pub fn edge_stats(edges: &EdgeDB) -> GraphStats {
+    let mut addresses = BTreeSet::new();
+    let mut tokens = BTreeSet::new();
+    let mut stats = GraphStats {
+        edge_count: edges.edge_count(),
+        ..Default::default()
+    };
+    for edge in edges.edges() {
+        addresses.insert(edge.from);
+        addresses.insert(edge.to);
+        tokens.insert(edge.token);
+        let digits = if edge.capacity == U256::from(0) {
+            stats.zero_capacity_edge_count += 1;
+            0
+        } else {
+            edge.capacity.to_decimal().len()
+        };
+        *stats.capacity_digits.entry(digits).or_default() += 1;
+    }
+    stats.address_count = addresses.len();
+    stats.token_count = tokens.len();
+
+    // Neighbors by index, several edges (with different tokens) count once.
+    let index = addresses
+        .iter()
+        .enumerate()
+        .map(|(i, address)| (*address, i))
+        .collect::<HashMap<Address, usize>>();
+    let mut neighbors = vec![BTreeSet::new(); addresses.len()];
+    let mut in_degrees = vec![0; addresses.len()];
+    for edge in edges.edges() {
+        if edge.capacity != U256::from(0) && neighbors[index[&edge.from]].insert(index[&edge.to]) {
+            in_degrees[index[&edge.to]] += 1;
+        }
+    }
+    for out in &neighbors {
+        *stats.out_degrees.entry(out.len()).or_default() += 1;
+    }
+    for degree in in_degrees {
+        *stats.in_degrees.entry(degree).or_default() += 1;
+    }
+    let adjacency = neighbors
+        .into_iter()
+        .map(|out| out.into_iter().collect())
+        .collect::<Vec<_>>();
+    stats.component_sizes = strongly_connected_components(&adjacency);
+    stats
+}
+
+/// Computes the statistics of the edges of a safe database, together with
+/// the number of safes that are not connected to any other safe.
+pub fn safe_db_stats(db: &DB) -> GraphStats {
+    let edges = db.edges();
+    let isolated = db
+        .safes()
+        .keys()
+        .filter(|safe| edges.outgoing(safe).is_empty() && edges.incoming(safe).is_empty())
+        .count();
+    GraphStats {
+        safe_count: Some(db.safes().len()),
+        isolated_safe_count: Some(isolated),
+        ..edge_stats(edges)
+    }
+}
+
+/// Returns the sizes of the strongly connected components of the graph, largest first.
+/// Uses Tarjan's algorithm without recursion, so that large graphs do not overflow the stack.
+fn strongly_connected_components(adjacency: &[Vec<usize>]) -> Vec<usize> {
+    let n = adjacency.len();
+    let mut index = vec![usize::MAX; n];
+    let mut low_link = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut stack = vec![];
+    let mut next_index = 0;
+    let mut sizes = vec![];
+    for root in 0..n {
+        if index[root] != usize::MAX {
+            continue;
+        }
+        // Nodes being visited, with the position of the next neighbor to visit.
+        let mut visiting = vec![(root, 0)];
+        index[root] = next_index;
+        low_link[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+        while let Some(&(node, position)) = visiting.last() {
+            if let Some(&neighbor) = adjacency[node].get(position) {
+                visiting.last_mut().unwrap().1 += 1;
+                if index[neighbor] == usize::MAX {
+                    index[neighbor] = next_index;
+                    low_link[neighbor] = next_index;
+                    next_index += 1;
+                    stack.push(neighbor);
+                    on_stack[neighbor] = true;
+                    visiting.push((neighbor, 0));
+                } else if on_stack[neighbor] {
+                    low_link[node] = min(low_link[node], index[neighbor]);
+                }
+                continue;
+            }
+            visiting.pop();
+            if let Some(&(parent, _)) = visiting.last() {
+                low_link[parent] = min(low_link[parent], low_link[node]);
+            }
+            if low_link[node] == index[node] {
+                let mut size = 0;
+                while let Some(member) = stack.pop() {
+                    on_stack[member] = false;
+                    size += 1;
+                    if member == node {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            }
+        }
+    }
+    sizes.sort_by(|a, b| b.cmp(a));
+    sizes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{addresses, edge};
+
+    #[test]
+    fn stats() {
+        let (a, b, c, d, ..) = addresses();
+        // a and b form a cycle (also via c), d is only reachable via a zero capacity edge.
+        let edges = EdgeDB::new(vec![
+            edge(a, b, a, 10),
+            edge(a, b, b, 5),
+            edge(b, c, b, 300),
+            edge(c, a, c, 7),
+            edge(b, a, b, 1),
+            edge(c, d, c, 0),
+        ]);
+        let stats = edge_stats(&edges);
+        assert_eq!(stats.address_count, 4);
+        assert_eq!(stats.token_count, 3);
+        assert_eq!(stats.edge_count, 6);
+        assert_eq!(stats.zero_capacity_edge_count, 1);
+        assert_eq!(stats.component_sizes, vec![3, 1]);
+        assert_eq!(stats.out_degrees, BTreeMap::from([(0, 1), (1, 2), (2, 1)]));
+        assert_eq!(stats.in_degrees, BTreeMap::from([(0, 1), (1, 2), (2, 1)]));
+        assert_eq!(
+            stats.capacity_digits,
+            BTreeMap::from([(0, 1), (1, 3), (2, 1), (3, 1)])
+        );
+        assert_eq!(stats.to_json()["components"]["largest"], 3);
+    }
+}
@@ -0,0 +1,24 @@
+//! Helpers shared by the unit tests.
+
+use crate::types::{Address, Edge, U256};
+
+/// Returns six distinct addresses.
+pub fn addresses() -> (Address, Address, Address, Address, Address, Address) {
+    (
+        Address::from("0x11C7e86fF693e9032A0F41711b5581a04b26Be2E"),
+        Address::from("0x22cEDde51198D1773590311E2A340DC06B24cB37"),
+        Address::from("0x33cEDde51198D1773590311E2A340DC06B24cB37"),
+        Address::from("0x447EDde51198D1773590311E2A340DC06B24cB37"),
+        Address::from("0x55c16ce62d26fd51582a646e2e30a3267b1e6d7e"),
+        Address::from("0x66c16ce62d26fd51582a646e2e30a3267b1e6d7e"),
+    )
+}
+
+pub fn edge(from: Address, to: Address, token: Address, capacity: u128) -> Edge {
+    Edge {
+        from,
+        to,
+        token,
+        capacity: U256::from(capacity),
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::time::SystemTime;
 
 use crate::types::Address;
 use crate::types::U256;
@@ -22,6 +23,10 @@ pub struct EdgeDB {
     edges: Vec<Edge>,
     outgoing: HashMap<Address, Vec<usize>>,
     incoming: HashMap<Address, Vec<usize>>,
+    /// The time the data of an edge was last updated, by (from, to, token).
+    updated_at: HashMap<(Address, Address, Address), SystemTime>,
+    /// The time the data of all edges not in `updated_at` was last updated, if known.
+    default_updated_at: Option<SystemTime>,
 }
 
 impl EdgeDB {
@@ -32,6 +37,7 @@ impl EdgeDB {
             edges,
             outgoing,
             incoming,
+            ..Default::default()
         }
     }
 
@@ -55,24 +61,67 @@ impl EdgeDB {
         }
     }
 
+    /// Like `update`, but also records when the data of the edge was retrieved.
+    pub fn update_at(&mut self, update: Edge, time: SystemTime) {
+        self.updated_at
+            .insert((update.from, update.to, update.token), time);
+        self.update(update);
+    }
+
+    /// Records when the data of all edges was retrieved, e.g. after loading them from a file.
+    pub fn set_updated_at(&mut self, time: SystemTime) {
+        self.updated_at.clear();
+        self.default_updated_at = Some(time);
+    }
+
+    /// Returns the time the data of the edge was last updated, if known.
+    pub fn updated_at(&self, edge: &Edge) -> Option<SystemTime> {
+        self.updated_at
+            .get(&(edge.from, edge.to, edge.token))
+            .copied()
+            .or(self.default_updated_at)
+    }
+
     pub fn outgoing(&self, source: &Address) -> Vec<&Edge> {
+        self.outgoing_iter(source).collect()
+    }
+
+    pub fn incoming(&self, to: &Address) -> Vec<&Edge> {
+        self.incoming_iter(to).collect()
+    }
+
+    /// Like `outgoing`, but without collecting the edges.
+    pub fn outgoing_iter<'a>(&'a self, source: &Address) -> impl Iterator<Item = &'a Edge> + 'a {
+        self.outgoing
+            .get(source)
+            .into_iter()
+            .flatten()
+            .map(|i| &self.edges[*i])
+            .filter(|e| e.capacity != U256::from(0))
+    }
+
+    /// Like `incoming`, but without collecting the edges.
+    pub fn incoming_iter<'a>(&'a self, to: &Address) -> impl Iterator<Item = &'a Edge> + 'a {
+        self.incoming
+            .get(to)
+            .into_iter()
+            .flatten()
+            .map(|i| &self.edges[*i])
+            .filter(|e| e.capacity != U256::from(0))
+    }
+
+    /// Like `outgoing`, but including the edges with zero capacity.
+    pub fn all_outgoing(&self, source: &Address) -> Vec<&Edge> {
         match self.outgoing.get(source) {
-            Some(out) => out
-                .iter()
-                .map(|i| self.edges.get(*i).unwrap())
-                .filter(|e| e.capacity != U256::from(0))
-                .collect(),
+            Some(out) => out.iter().map(|i| &self.edges[*i]).collect(),
             None => vec![],
         }
     }
 
-    pub fn incoming(&self, to: &Address) -> Vec<&Edge> {
+    /// Like `incoming`, but including the edges with zero capacity.
+    pub fn all_incoming(&self, to: &Address) -> Vec<&Edge> {
         match self.incoming.get(to) {
-            Some(incoming) => incoming
-                .iter()
-                .map(|i| self.edges.get(*i).unwrap())
-                .filter(|e| e.capacity != U256::from(0))
-                .collect(),
+            Some(incoming) => incoming.iter().map(|i| &self.edges[*i]).collect(),
             None => vec![],
         }
     }
@@ -89,6 +138,153 @@ impl EdgeDB {
     }
 }
 
+/// Temporary additions, removals and capacity changes of edges that are applied
+/// on top of an `EdgeDB` without modifying (or cloning) it, e.g. for what-if queries.
+#[derive(Debug, Default, Clone)]
+pub struct EdgeOverlay {
+    /// The new capacity by (from, to, token). A capacity of zero removes the edge.
+    capacities: HashMap<(Address, Address, Address), U256>,
+    /// (to, token) of the changed edges by sender.
+    outgoing: HashMap<Address, Vec<(Address, Address)>>,
+    /// (from, token) of the changed edges by receiver.
+    incoming: HashMap<Address, Vec<(Address, Address)>>,
+}
+
+impl EdgeOverlay {
+    pub fn new(changes: Vec<Edge>) -> EdgeOverlay {
+        let mut overlay = EdgeOverlay::default();
+        for change in changes {
+            overlay.set(change);
+        }
+        overlay
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.capacities.is_empty()
+    }
+
+    /// Adds the edge or changes its capacity. A capacity of zero removes it.
+    pub fn set(&mut self, edge: Edge) {
+        let key = (edge.from, edge.to, edge.token);
+        if self.capacities.insert(key, edge.capacity).is_none() {
+            self.outgoing
+                .entry(edge.from)
+                .or_default()
+                .push((edge.to, edge.token));
+            self.incoming
+                .entry(edge.to)
+                .or_default()
+                .push((edge.from, edge.token));
+        }
+    }
+
+    /// Returns the changed edges with their new capacities (zero for removed edges).
+    pub fn changes(&self) -> impl Iterator<Item = Edge> + '_ {
+        self.capacities
+            .iter()
+            .map(|(&(from, to, token), &capacity)| Edge {
+                from,
+                to,
+                token,
+                capacity,
+            })
+    }
+
+    /// Applies the changes of `other` on top of these changes.
+    pub fn extend(&mut self, other: &EdgeOverlay) {
+        for change in other.changes() {
+            self.set(change);
+        }
+    }
+
+    /// Reduces the capacities of the edges of `base` (with the changes so far applied)
+    /// to account for the given (planned or executed) transfers and records them as changes.
+    ///
+    /// Since the capacity of an edge is limited by the balance of the sender and the
+    /// trust limit of the receiver, a transfer does not only reduce the capacity of
+    /// its own edge, but also that of all other edges sending the same token from the
+    /// same sender and (unless the tokens are sent back to their owner) those sending
+    /// the same token to the same receiver. Received tokens are not taken into account,
+    /// so the resulting capacities are a lower bound.
+    pub fn reduce_capacities(&mut self, base: &EdgeDB, transfers: &[Edge]) {
+        for transfer in transfers {
+            let mut affected = self.outgoing(base, &transfer.from);
+            if transfer.to != transfer.token {
+                affected.extend(self.incoming(base, &transfer.to));
+            }
+            affected.retain(|e| e.token == transfer.token);
+            affected.sort();
+            affected.dedup();
+            for edge in affected {
+                self.set(Edge {
+                    capacity: if edge.capacity > transfer.capacity {
+                        edge.capacity - transfer.capacity
+                    } else {
+                        U256::from(0)
+                    },
+                    ..edge
+                });
+            }
+        }
+    }
+
+    /// Like `EdgeDB::outgoing`, but with the changes applied to the edges of `base`.
+    pub fn outgoing(&self, base: &EdgeDB, from: &Address) -> Vec<Edge> {
+        self.apply(base.outgoing(from), self.changed_outgoing(from))
+    }
+
+    /// Like `EdgeDB::incoming`, but with the changes applied to the edges of `base`.
+    pub fn incoming(&self, base: &EdgeDB, to: &Address) -> Vec<Edge> {
+        self.apply(base.incoming(to), self.changed_incoming(to))
+    }
+
+    /// Like `EdgeDB::all_outgoing`, but with the changes applied to the edges of `base`.
+    /// Edges removed by the changes are not included.
+    pub fn all_outgoing(&self, base: &EdgeDB, from: &Address) -> Vec<Edge> {
+        self.apply(base.all_outgoing(from), self.changed_outgoing(from))
+    }
+
+    /// Like `EdgeDB::all_incoming`, but with the changes applied to the edges of `base`.
+    /// Edges removed by the changes are not included.
+    pub fn all_incoming(&self, base: &EdgeDB, to: &Address) -> Vec<Edge> {
+        self.apply(base.all_incoming(to), self.changed_incoming(to))
+    }
+
+    fn changed_outgoing<'a>(&'a self, from: &'a Address) -> impl Iterator<Item = Edge> + 'a {
+        self.outgoing
+            .get(from)
+            .into_iter()
+            .flatten()
+            .map(|(to, token)| Edge {
+                from: *from,
+                to: *to,
+                token: *token,
+                capacity: self.capacities[&(*from, *to, *token)],
+            })
+    }
+
+    fn changed_incoming<'a>(&'a self, to: &'a Address) -> impl Iterator<Item = Edge> + 'a {
+        self.incoming
+            .get(to)
+            .into_iter()
+            .flatten()
+            .map(|(from, token)| Edge {
+                from: *from,
+                to: *to,
+                token: *token,
+                capacity: self.capacities[&(*from, *to, *token)],
+            })
+    }
+
+    fn apply(&self, base: Vec<&Edge>, changed: impl Iterator<Item = Edge>) -> Vec<Edge> {
+        base.into_iter()
+            .filter(|e| !self.capacities.contains_key(&(e.from, e.to, e.token)))
+            .copied()
+            .chain(changed.filter(|e| e.capacity != U256::from(0)))
+            .collect()
+    }
+}
+
 fn outgoing_index(edges: &[Edge]) -> HashMap<Address, Vec<usize>> {
     let mut index: HashMap<Address, Vec<usize>> = HashMap::new();
     for (i, e) in edges.iter().enumerate() {
@@ -2,7 +2,7 @@ use std::{cmp::min, collections::BTreeMap};
 
 use super::{Address, U256};
 
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct Safe {
     /// The address of the token, or the address of the safe if
     /// the database does not use the distinction.
@@ -48,7 +48,7 @@ fn test_flow(
     requested_flow: U256,
     max_distance: Option<u64>,
 ) {
-    let transfers = compute_flow(source, sink, edges, requested_flow, max_distance, None);
+    let transfers = compute_flow(source, sink, edges, requested_flow, max_distance, None).unwrap();
     println!("{transfers:?}");
 
     let token_owners = transfers